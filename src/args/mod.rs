@@ -2,6 +2,12 @@ pub mod parse;
 
 use std::str::FromStr;
 
+use dups::filter::RootSpan;
+use dups::heuristics::{Attribute, Stage};
+use dups::metadata::MetadataPolicy;
+use fs_entry::Priority;
+use utils::reader::ReadOrder;
+
 pub const EXCLUDES : [&str; 2] = [ ".git", ".svn" ];
 
 #[derive(Clone)]
@@ -11,21 +17,48 @@ pub enum DedupAction {
     Symlink, Hardlink, Print,
 }
 
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+pub enum ProgressFormat {
+    None, Bar, Json,
+}
+
 pub struct Args {
     pub paths           : Vec<String>,
+    pub references      : Vec<String>,
     pub action          : DedupAction,
     pub result_path     : Option<String>,
     pub includes        : Vec<String>,
     pub excludes        : Vec<String>,
     pub abort_on_error  : bool,
-    pub show_progress   : bool,
+    pub error_report    : Option<String>,
+    pub write_partial   : bool,
+    pub journal         : Option<String>,
+    pub undo            : Option<String>,
+    pub emit_script     : Option<String>,
+    pub metadata        : MetadataPolicy,
+    pub same            : Vec<Attribute>,
+    pub stages          : Vec<Stage>,
+    pub progress        : ProgressFormat,
     pub verbosity       : String,
     pub one_file_system : bool,
+    pub skip_fstypes    : Vec<String>,
+    pub only_fstypes    : Vec<String>,
     pub hash            : String,
     pub n_read          : usize,
+    pub n_samples       : usize,
+    pub compare_max     : usize,
+    pub io_hints        : bool,
+    pub max_read_rate   : Option<u64>,
+    pub idle_io         : bool,
+    pub read_order      : ReadOrder,
+    pub small_size      : u64,
     pub min_file_size   : Option<u64>,
     pub max_file_size   : Option<u64>,
     pub dry_run         : bool,
+    pub root_span       : RootSpan,
+    pub with_roots      : Vec<Priority>,
 }
 
 impl FromStr for DedupAction {
@@ -41,3 +74,73 @@ impl FromStr for DedupAction {
     }
 }
 
+
+impl FromStr for ProgressFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(ProgressFormat::None),
+            "bar"  => Ok(ProgressFormat::Bar),
+            "json" => Ok(ProgressFormat::Json),
+            _      => Err(format!("Cannot parse progress format: {}", s)),
+        }
+    }
+}
+
+impl FromStr for MetadataPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ignore"    => Ok(MetadataPolicy::Ignore),
+            "refuse"    => Ok(MetadataPolicy::Refuse),
+            "split"     => Ok(MetadataPolicy::Split),
+            "reconcile" => Ok(MetadataPolicy::Reconcile),
+            _           => Err(format!("Cannot parse metadata policy: {}", s)),
+        }
+    }
+}
+
+impl FromStr for ReadOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "inode"    => Ok(ReadOrder::Inode),
+            "physical" => Ok(ReadOrder::Physical),
+            _          => Err(format!("Cannot parse read order: {}", s)),
+        }
+    }
+}
+
+impl FromStr for Attribute {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "owner"  => Ok(Attribute::Owner),
+            "group"  => Ok(Attribute::Group),
+            "mode"   => Ok(Attribute::Mode),
+            "xattrs" => Ok(Attribute::XAttrs),
+            _        => Err(format!("Cannot parse attribute: {}", s)),
+        }
+    }
+}
+
+impl FromStr for Stage {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "first"   => Ok(Stage::FirstBytes),
+            "middle"  => Ok(Stage::MiddleBytes),
+            "last"    => Ok(Stage::LastBytes),
+            "samples" => Ok(Stage::Samples),
+            "hash"    => Ok(Stage::Hash),
+            "blocks"  => Ok(Stage::BlockHash),
+            "compare" => Ok(Stage::Compare),
+            _         => Err(format!("Cannot parse stage: {}", s)),
+        }
+    }
+}
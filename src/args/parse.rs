@@ -1,8 +1,23 @@
+use std::ffi::OsString;
 use std::str::FromStr;
 use clap::{Arg, App, ArgMatches};
 use humanize_rs::bytes::Bytes;
 
-use args::{Args, EXCLUDES, DedupAction};
+use error::{self, Error};
+use args::{Args, EXCLUDES, DedupAction, ProgressFormat};
+use dups::filter::RootSpan;
+use dups::heuristics::{Attribute, Stage};
+use dups::metadata::MetadataPolicy;
+use utils::reader::ReadOrder;
+use fs_entry::Priority;
+
+fn is_integer(s : String) -> Result<(), String>
+{
+    match Priority::from_str(&s) {
+        Ok(_)  => { Ok(()) },
+        Err(e) => { Err(format!("Failed to parse integer {}: {}", s, e)) },
+    }
+}
 
 fn is_numeric(s : String) -> Result<(), String>
 {
@@ -20,10 +35,22 @@ macro_rules! construct_parser {
             .set_term_width(80)
             .arg(Arg::with_name("paths")
                 .help("Root path(s) to deduplicate files from")
-                .required(true)
+                .required_unless("undo")
                 .multiple(true)
                 .value_name("PATHS")
             )
+            .arg(Arg::with_name("reference")
+                .short("r")
+                .long("--reference")
+                .help(
+                    "Read-only root path(s). Files under them are used to \
+                     find duplicates, but are never removed or replaced"
+                )
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("REFERENCE")
+            )
             .arg(Arg::with_name("action")
                 .short("a")
                 .long("--action")
@@ -33,6 +60,33 @@ macro_rules! construct_parser {
                 .default_value("print")
                 .value_name("ACTION")
             )
+            .arg(Arg::with_name("metadata")
+                .long("--metadata")
+                .possible_values(&["ignore", "refuse", "split", "reconcile"])
+                .default_value("ignore")
+                .takes_value(true)
+                .value_name("POLICY")
+                .help(
+                    "How to treat duplicates with different owner, mode or \
+                    xattrs. 'refuse' keeps files that differ from the group \
+                    leader, 'split' links only files with equal metadata, \
+                    'reconcile' gives the leader the newest mtime and the \
+                    union of read permissions"
+                )
+            )
+            .arg(Arg::with_name("same")
+                .long("--same")
+                .possible_values(&["owner", "group", "mode", "xattrs"])
+                .help(
+                    "Only consider files duplicates if they also share these \
+                     attributes (e.g. owner,mode)"
+                )
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .use_delimiter(true)
+                .value_name("ATTR")
+            )
             .arg(Arg::with_name("output")
                 .short("o")
                 .long("--output")
@@ -68,6 +122,47 @@ macro_rules! construct_parser {
                 .long("--sloppy")
                 .help("Ignore most I/O errors and skip corresponding files")
             )
+            .arg(Arg::with_name("error_report")
+                .long("--error-report")
+                .takes_value(true)
+                .help("Write errors of skipped files to this file")
+            )
+            .arg(Arg::with_name("write_partial")
+                .long("--write-partial")
+                .help(
+                    "If interrupted before deduplication, save possible \
+                    duplicates found so far to the output file"
+                )
+            )
+            .arg(Arg::with_name("journal")
+                .long("--journal")
+                .takes_value(true)
+                .value_name("JOURNAL")
+                .help(
+                    "Append every replaced file with its original metadata \
+                    to this journal, so that the run can be undone"
+                )
+            )
+            .arg(Arg::with_name("undo")
+                .long("--undo")
+                .takes_value(true)
+                .value_name("JOURNAL")
+                .conflicts_with("journal")
+                .help(
+                    "Turn links recorded in the journal back into \
+                    independent copies with their original metadata"
+                )
+            )
+            .arg(Arg::with_name("emit_script")
+                .long("--emit-script")
+                .takes_value(true)
+                .value_name("SCRIPT")
+                .conflicts_with_all(&[ "dry_run", "journal" ])
+                .help(
+                    "Do not alter filesystem. Write a shell script that \
+                    performs the deduplication when run later"
+                )
+            )
             .arg(Arg::with_name("dry_run")
                 .long("--dry-run")
                 .help(
@@ -79,6 +174,17 @@ macro_rules! construct_parser {
                 .long("--no-progress")
                 .help("Do not show command progress")
             )
+            .arg(Arg::with_name("progress")
+                .long("--progress")
+                .possible_values(&["none", "bar", "json"])
+                .help(
+                    "Progress report format. 'json' prints progress events \
+                     to stderr as JSON lines"
+                )
+                .takes_value(true)
+                .default_value("bar")
+                .value_name("FORMAT")
+            )
             .arg(Arg::with_name("v")
                 .short("v")
                 .multiple(true)
@@ -94,14 +200,64 @@ macro_rules! construct_parser {
                 .long("--one-file-system")
                 .help("Do not cross filesystem boundaries")
             )
+            .arg(Arg::with_name("skip_fstype")
+                .long("--skip-fstype")
+                .help(
+                    "Do not descend into filesystems of these types \
+                     (e.g. proc,nfs,fuse.sshfs)"
+                )
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .use_delimiter(true)
+                .value_name("FSTYPE")
+            )
+            .arg(Arg::with_name("only_fstype")
+                .long("--only-fstype")
+                .help("Only descend into filesystems of these types")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .use_delimiter(true)
+                .value_name("FSTYPE")
+            )
             .arg(Arg::with_name("hash")
                 .long("--hash")
-                .possible_values(&["md5", "sha1", "sha256", "sha512" ])
-                .help("File hashing function")
+                .possible_values(
+                    &["md5", "sha1", "sha256", "sha512", "blake3", "xxh3"]
+                )
+                .help(
+                    "File hashing function. Groups of 'xxh3' hashes are \
+                     verified by comparison"
+                )
                 .takes_value(true)
                 .default_value("sha512")
                 .value_name("HASH")
             )
+            .arg(Arg::with_name("stages")
+                .long("--stages")
+                .possible_values(
+                    &[
+                        "first", "middle", "last", "samples", "hash",
+                        "blocks", "compare"
+                    ]
+                )
+                .help(
+                    "Stages that narrow down files of equal size, in order. \
+                     Byte stages compare NREAD bytes at the start, middle \
+                     or end of files, 'samples' at NSAMPLES offsets spread \
+                     across them. 'blocks' hashes files in growing \
+                     blocks and drops unique files after each block. \
+                     'compare' compares files byte by byte. The last stage \
+                     must be 'hash', 'blocks' or 'compare'"
+                )
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .use_delimiter(true)
+                .default_value("first,last,hash")
+                .value_name("STAGE")
+            )
             .arg(Arg::with_name("n_read")
                 .long("--nread")
                 .help(
@@ -113,6 +269,75 @@ macro_rules! construct_parser {
                 .value_name("NREAD")
                 .validator(is_numeric)
             )
+            .arg(Arg::with_name("n_samples")
+                .long("--nsamples")
+                .help(
+                     "Number of places the 'samples' stage reads NREAD bytes \
+                      from"
+                )
+                .takes_value(true)
+                .default_value("16")
+                .value_name("NSAMPLES")
+                .validator(is_integer)
+            )
+            .arg(Arg::with_name("no_io_hints")
+                .long("--no-io-hints")
+                .help(
+                    "Update atime of read files and leave them in the page \
+                     cache"
+                )
+            )
+            .arg(Arg::with_name("max_read_rate")
+                .long("--max-read-rate")
+                .help(
+                    "Maximum number of bytes read per second (e.g. 50M), \
+                     0 for unlimited"
+                )
+                .takes_value(true)
+                .value_name("RATE")
+                .validator(is_numeric)
+            )
+            .arg(Arg::with_name("idle_io")
+                .long("--idle-io")
+                .help(
+                    "Only read files when no other process uses the disk \
+                     (idle I/O scheduling class)"
+                )
+            )
+            .arg(Arg::with_name("small_size")
+                .long("--small-size")
+                .help(
+                    "Files smaller than this are read whole by the first \
+                     content stage and never opened again. 0 disables"
+                )
+                .takes_value(true)
+                .default_value("16K")
+                .value_name("SIZE")
+                .validator(is_numeric)
+            )
+            .arg(Arg::with_name("read_order")
+                .long("--read-order")
+                .possible_values(&["inode", "physical"])
+                .help(
+                    "Order of reads within a stage. 'physical' follows the \
+                     location of files on disk (FIEMAP), which is faster \
+                     on rotational disks with aged filesystems"
+                )
+                .takes_value(true)
+                .default_value("inode")
+                .value_name("ORDER")
+            )
+            .arg(Arg::with_name("compare_max")
+                .long("--compare-max")
+                .help(
+                     "Compare groups of up to N files of equal size byte by \
+                      byte instead of hashing them. 0 disables comparison"
+                )
+                .takes_value(true)
+                .default_value("2")
+                .value_name("N")
+                .validator(is_integer)
+            )
             .arg(Arg::with_name("min_file_size")
                 .long("--min-size")
                 .help("Minimum file size to consider")
@@ -121,6 +346,28 @@ macro_rules! construct_parser {
                 .value_name("MIN_FILE_SIZE")
                 .validator(is_numeric)
             )
+            .arg(Arg::with_name("cross_root_only")
+                .long("--cross-root-only")
+                .conflicts_with("within_root_only")
+                .help("Only consider duplicates that span multiple roots")
+            )
+            .arg(Arg::with_name("within_root_only")
+                .long("--within-root-only")
+                .help("Only consider duplicates that belong to a single root")
+            )
+            .arg(Arg::with_name("with_root")
+                .long("--with-root")
+                .help(
+                    "Only consider duplicates that include files from root \
+                     N. Roots are numbered from 0 in the order they are \
+                     specified, reference roots follow regular ones"
+                )
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("N")
+                .validator(is_integer)
+            )
             .arg(Arg::with_name("max_file_size")
                 .long("--max-size")
                 .help("Maximum file size to consider")
@@ -151,15 +398,31 @@ impl Args {
     }
 
     fn parse_includes(matches : &ArgMatches) -> Vec<String> {
+        Self::parse_strings(matches, "include")
+    }
+
+    fn parse_strings(matches : &ArgMatches, name : &str) -> Vec<String> {
         let mut result : Vec<String> = Vec::new();
 
-        if let Some(includes) = matches.values_of("include") {
-            result = includes.map(|x| { x.to_string() }).collect();
+        if let Some(values) = matches.values_of(name) {
+            result = values.map(|x| { x.to_string() }).collect();
         }
 
         result
     }
 
+    fn parse_root_span(matches : &ArgMatches) -> RootSpan {
+        if matches.is_present("cross_root_only") {
+            RootSpan::CrossRoot
+        }
+        else if matches.is_present("within_root_only") {
+            RootSpan::WithinRoot
+        }
+        else {
+            RootSpan::Any
+        }
+    }
+
     #[allow(unused_parens)]
     fn parse_verbosity(matches : &ArgMatches) -> &'static str {
         let n : i64 = (
@@ -182,45 +445,187 @@ impl Args {
     }
 
     pub fn parse() -> Self {
-        let matches = construct_parser!().get_matches();
+        Self::from_matches(&construct_parser!().get_matches())
+    }
+
+    pub fn parse_from<I, T>(argv : I) -> Self
+        where I : IntoIterator<Item = T>,
+              T : Into<OsString> + Clone
+    {
+        Self::from_matches(&construct_parser!().get_matches_from(argv))
+    }
 
-        let paths : Vec<String> = matches.values_of("paths").unwrap()
-            .map(|x| { x.to_string() }).collect();
+    fn from_matches(matches : &ArgMatches) -> Self {
+
+        let paths = Self::parse_strings(matches, "paths");
 
         let action : DedupAction = DedupAction::from_str(
             matches.value_of("action").unwrap()
         ).unwrap();
 
+        let references   = Self::parse_strings(matches, "reference");
+        let skip_fstypes = Self::parse_strings(matches, "skip_fstype");
+        let only_fstypes = Self::parse_strings(matches, "only_fstype");
+
         let result_path : Option<String>
             = matches.value_of("output").map(|x| { x.to_string() });
+        let error_report : Option<String>
+            = matches.value_of("error_report").map(|x| { x.to_string() });
+        let journal : Option<String>
+            = matches.value_of("journal").map(|x| { x.to_string() });
+        let undo : Option<String>
+            = matches.value_of("undo").map(|x| { x.to_string() });
+        let emit_script : Option<String>
+            = matches.value_of("emit_script").map(|x| { x.to_string() });
 
-        let excludes = Self::parse_excludes(&matches);
-        let includes = Self::parse_includes(&matches);
+        let excludes = Self::parse_excludes(matches);
+        let includes = Self::parse_includes(matches);
 
         let abort_on_error  : bool = ! matches.is_present("sloppy");
-        let show_progress   : bool = ! matches.is_present("no_progress");
         let one_file_system : bool = matches.is_present("one_file_system");
         let dry_run         : bool = matches.is_present("dry_run");
+        let io_hints        : bool = ! matches.is_present("no_io_hints");
+        let idle_io         : bool = matches.is_present("idle_io");
+        let write_partial   : bool = matches.is_present("write_partial");
+
+        let metadata = MetadataPolicy::from_str(
+            matches.value_of("metadata").unwrap()
+        ).unwrap();
+
+        let same : Vec<Attribute> = matches.values_of("same")
+            .map(|x| { x.map(|y| Attribute::from_str(y).unwrap()).collect() })
+            .unwrap_or_default();
+
+        let stages : Vec<Stage> = matches.values_of("stages").unwrap()
+            .map(|x| { Stage::from_str(x).unwrap() })
+            .collect();
 
-        let verbosity = Args::parse_verbosity(&matches).to_string();
+        let progress = if matches.is_present("no_progress") {
+            ProgressFormat::None
+        }
+        else {
+            ProgressFormat::from_str(matches.value_of("progress").unwrap())
+                .unwrap()
+        };
+
+        let verbosity = Args::parse_verbosity(matches).to_string();
         let hash      = matches.value_of("hash").unwrap().to_string();
 
         let n_read : usize = usize::from_str(
             matches.value_of("n_read").unwrap()
         ).unwrap();
 
+        let n_samples : usize = usize::from_str(
+            matches.value_of("n_samples").unwrap()
+        ).unwrap();
+
+        let compare_max : usize = usize::from_str(
+            matches.value_of("compare_max").unwrap()
+        ).unwrap();
+
         let min_file_size : Option<u64> = matches.value_of("min_file_size")
             .map( |x| Bytes::from_str(x).unwrap().size() );
 
         let max_file_size : Option<u64> = matches.value_of("max_file_size")
             .map( |x| Bytes::from_str(x).unwrap().size() );
 
+        let read_order = ReadOrder::from_str(
+            matches.value_of("read_order").unwrap()
+        ).unwrap();
+
+        let small_size : u64 = Bytes::from_str(
+            matches.value_of("small_size").unwrap()
+        ).unwrap().size();
+
+        let max_read_rate : Option<u64> = matches.value_of("max_read_rate")
+            .map( |x| Bytes::from_str(x).unwrap().size() );
+
+        let root_span = Self::parse_root_span(matches);
+        let with_roots : Vec<Priority> = matches.values_of("with_root")
+            .map(|x| { x.map(|y| Priority::from_str(y).unwrap()).collect() })
+            .unwrap_or_default();
+
         Args {
-            paths, action, result_path, includes, excludes, abort_on_error,
-            show_progress, verbosity, one_file_system, hash, n_read,
-            min_file_size, max_file_size, dry_run
+            paths, references, action, result_path, includes, excludes,
+            abort_on_error, error_report, write_partial, journal, undo,
+            emit_script, metadata, same, stages, progress, verbosity,
+            one_file_system, skip_fstypes, only_fstypes, hash, n_read,
+            n_samples, compare_max, io_hints, max_read_rate, idle_io,
+            read_order, small_size, min_file_size, max_file_size, dry_run,
+            root_span, with_roots
+        }
+    }
+
+    // Checks what clap cannot check value by value
+    pub fn check(&self) -> error::Result<()> {
+        let n_roots = self.paths.len() + self.references.len();
+
+        for root in self.with_roots.iter() {
+            if (*root as usize) >= n_roots {
+                return Err(Error::Config(format!(
+                    "Root {} given to --with-root does not exist, \
+                     there are {} roots", root, n_roots
+                )));
+            }
+        }
+
+        if self.emit_script.is_some() {
+            if self.action == DedupAction::Print {
+                return Err(Error::Config(
+                    "--emit-script requires action 'hardlink' or 'symlink'"
+                        .to_string()
+                ));
+            }
+
+            if self.metadata == MetadataPolicy::Reconcile {
+                return Err(Error::Config(
+                    "--emit-script cannot reconcile metadata".to_string()
+                ));
+            }
         }
+
+        Ok(())
     }
 
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_with_root_range() {
+        let args = Args::parse_from(
+            vec!["fdedup", "a", "-r", "b", "--with-root", "1"]
+        );
+        assert!(args.check().is_ok());
+
+        let args = Args::parse_from(
+            vec!["fdedup", "a", "-r", "b", "--with-root", "2"]
+        );
+        match args.check() {
+            Err(Error::Config(_)) => {},
+            _                     => panic!("--with-root 2 accepted"),
+        }
+    }
+
+    #[test]
+    fn test_emit_script_conflicts() {
+        let args = Args::parse_from(
+            vec!["fdedup", "a", "--emit-script", "s.sh"]
+        );
+        assert!(args.check().is_err());
+
+        let args = Args::parse_from(vec![
+            "fdedup", "a", "--emit-script", "s.sh", "--action", "hardlink",
+            "--metadata", "reconcile"
+        ]);
+        assert!(args.check().is_err());
+
+        let args = Args::parse_from(vec![
+            "fdedup", "a", "--emit-script", "s.sh", "--action", "hardlink"
+        ]);
+        assert!(args.check().is_ok());
+    }
+}
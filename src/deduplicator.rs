@@ -0,0 +1,520 @@
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use log::Level;
+
+use args::DedupAction;
+use error::{Error, Result};
+use fs_entry::{FSEntry, Priority, ScanConfig};
+use dups::search::{
+    remove_unique_entries_by_stage, remove_unique_entries_by_heuristic,
+    remove_unique_entries_by_comparison, group_by_heuristic
+};
+use dups::hasher::HashAlgorithm;
+use dups::heuristics::{
+    Attribute, FileHash, Heuristic, Stage, DEFAULT_STAGES, block_hash_rounds
+};
+use dups::compare::MAX_COMPARE_FILES;
+use dups::dedup::{DedupSummary, deduplicate};
+use dups::journal::Journal;
+use dups::metadata::{
+    MetadataPolicy, apply_metadata_policy, reconcile_metadata
+};
+use dups::script::write_script;
+use dups::filter::{GroupFilter, RootSpan, filter_groups};
+use utils::error_log::{ErrorLog, SkippedError};
+use utils::interrupt::Interrupt;
+use utils::reader::{ReadOrder, Reader};
+use utils::progress::{NullProgress, ProgressSink};
+
+#[derive(Clone)]
+#[derive(Debug)]
+#[derive(Default)]
+#[derive(PartialEq)]
+#[derive(Eq)]
+pub struct DuplicateStats {
+    pub n_files       : usize,
+    pub n_inodes      : usize,
+    pub n_groups      : usize,
+    pub n_dupl_files  : usize,
+    pub n_dupl_inodes : usize,
+    pub saved_size    : u64,
+}
+
+// Interrupted searches are not complete, their groups are only candidates
+// that passed the stages finished before the interruption
+pub struct DedupReport {
+    pub groups   : Vec<Vec<FSEntry>>,
+    pub stats    : DuplicateStats,
+    pub complete : bool,
+    pub dedup    : DedupSummary,
+}
+
+pub struct Deduplicator {
+    pub scan      : ScanConfig,
+    pub n_read    : usize,
+    pub n_samples : usize,
+    pub hash      : HashAlgorithm,
+    pub action    : DedupAction,
+    pub dry_run   : bool,
+    pub plan      : RefCell<Box<dyn Write>>,
+    pub filter    : GroupFilter,
+    pub progress  : ErrorLog,
+    pub interrupt : Interrupt,
+    pub journal   : Option<String>,
+    pub metadata  : MetadataPolicy,
+    pub same      : Vec<Attribute>,
+    pub stages    : Vec<Stage>,
+    pub compare   : usize,
+    pub reader    : Reader,
+}
+
+fn log_possible_duplicates(entries : &[FSEntry], name : &str) {
+    if log_enabled!(Level::Info) {
+        let n_entries = entries.len();
+        let n_files : usize = entries.iter().map( |x| x.paths.len() ).sum();
+
+        info!(
+            "Possibly identical files after grouping by {}: {} (inodes: {}).",
+            name, n_files, n_entries
+        );
+    }
+
+    trace!("Entries: {:#?}", entries);
+}
+
+impl DuplicateStats {
+
+    pub fn new(entries : &[FSEntry]) -> Self {
+        DuplicateStats {
+            n_files  : entries.iter().map( |x| x.paths.len() ).sum(),
+            n_inodes : entries.len(),
+            ..Default::default()
+        }
+    }
+
+    pub fn count_groups(&mut self, duplicate_groups : &[Vec<FSEntry>]) {
+        for group in duplicate_groups.iter() {
+            let group_size : usize = group.len();
+
+            self.n_groups      += 1;
+            self.n_dupl_inodes += group_size - 1;
+            self.n_dupl_files  += group.iter().skip(1).map(
+                |x| x.paths.len()
+            ).sum::<usize>();
+
+            self.saved_size += ((group_size - 1) as u64) * group[0].size;
+        }
+    }
+}
+
+impl Deduplicator {
+
+    pub fn new(scan : ScanConfig) -> Self {
+        Deduplicator {
+            scan,
+            n_read    : 128,
+            n_samples : 16,
+            hash      : HashAlgorithm::Sha512,
+            action    : DedupAction::Print,
+            dry_run   : false,
+            plan      : RefCell::new(Box::new(io::sink())),
+            filter    : GroupFilter::new(RootSpan::Any, Vec::new()),
+            progress  : ErrorLog::new(Box::new(NullProgress)),
+            interrupt : Interrupt::new(),
+            journal   : None,
+            metadata  : MetadataPolicy::Ignore,
+            same      : Vec::new(),
+            stages    : DEFAULT_STAGES.to_vec(),
+            compare   : 2,
+            reader    : Reader::new(),
+        }
+    }
+
+    pub fn n_read(mut self, n_read : usize) -> Self {
+        self.n_read = n_read;
+        self
+    }
+
+    // Number of places the `Samples` stage reads `n_read` bytes from
+    pub fn n_samples(mut self, n_samples : usize) -> Self {
+        self.n_samples = n_samples;
+        self
+    }
+
+    // Groups of a non-cryptographic hash are verified by comparison
+    pub fn hash(mut self, hash : HashAlgorithm) -> Self {
+        let digest = if hash.is_cryptographic() {
+            hash
+        }
+        else {
+            HashAlgorithm::Blake3
+        };
+
+        self.hash   = hash;
+        self.reader = self.reader.digest(digest);
+        self
+    }
+
+    // Files smaller than this are read once, by the first content stage
+    pub fn small_size(mut self, size : u64) -> Self {
+        self.reader = self.reader.small_size(size);
+        self
+    }
+
+    pub fn action(mut self, action : DedupAction) -> Self {
+        self.action = action;
+        self
+    }
+
+    pub fn dry_run(mut self, value : bool) -> Self {
+        self.dry_run = value;
+        self
+    }
+
+    // Dry run plan is discarded unless an output is given
+    pub fn plan_output(mut self, out : Box<dyn Write>) -> Self {
+        self.plan = RefCell::new(out);
+        self
+    }
+
+    pub fn root_span(mut self, span : RootSpan) -> Self {
+        self.filter.span = span;
+        self
+    }
+
+    pub fn with_root(mut self, root : Priority) -> Self {
+        self.filter.with_roots.push(root);
+        self
+    }
+
+    pub fn progress(mut self, progress : Box<dyn ProgressSink>) -> Self {
+        self.progress = ErrorLog::new(progress);
+        self
+    }
+
+    pub fn interrupt(mut self, interrupt : Interrupt) -> Self {
+        self.interrupt = interrupt;
+        self
+    }
+
+    // Every replaced file is appended to this journal, see `undo_journal`
+    pub fn journal(mut self, path : &str) -> Self {
+        self.journal = Some(path.to_string());
+        self
+    }
+
+    pub fn metadata_policy(mut self, policy : MetadataPolicy) -> Self {
+        self.metadata = policy;
+        self
+    }
+
+    // Duplicates must also share this attribute
+    pub fn require_same(mut self, attr : Attribute) -> Self {
+        if ! self.same.contains(&attr) {
+            self.same.push(attr);
+        }
+        self
+    }
+
+    // Stages in the order they run. The last one must be exact, as each
+    // stage replaces the grouping of the previous one.
+    pub fn stages(mut self, stages : &[Stage]) -> Self {
+        self.stages = stages.to_vec();
+        self
+    }
+
+    // The hash stage compares groups of up to this many files directly
+    // instead. Values below 2 disable comparison.
+    pub fn compare_max(mut self, max : usize) -> Self {
+        self.compare = max;
+        self
+    }
+
+    // Reads skip atime updates and drop cached pages of each file once it is
+    // read. Disable to keep files in the page cache.
+    pub fn io_hints(mut self, value : bool) -> Self {
+        self.reader = self.reader.hints(value);
+        self
+    }
+
+    pub fn read_order(mut self, order : ReadOrder) -> Self {
+        self.reader = self.reader.order(order);
+        self
+    }
+
+    // Limits bytes read per second by all stages together, 0 is unlimited
+    pub fn max_read_rate(mut self, rate : Option<u64>) -> Self {
+        self.reader = self.reader.max_rate(rate);
+        self
+    }
+
+    // Errors reported so far, including those skipped in sloppy mode
+    pub fn skipped_errors(&self) -> Vec<SkippedError> {
+        self.progress.errors()
+    }
+
+    fn cmp_dev(&self) -> bool {
+        self.action == DedupAction::Hardlink
+    }
+
+    fn verifies(&self, stage : &Stage) -> bool {
+        matches!(stage, Stage::Hash | Stage::BlockHash)
+            && ! self.hash.is_cryptographic()
+    }
+
+    fn check_stages(&self) -> Result<()> {
+        let exact = self.stages.last().map(
+            |x| {
+                let heuristic = x.heuristic(
+                    self.n_read, self.n_samples, self.hash
+                );
+
+                self.verifies(x) || heuristic.is_exact()
+            }
+        );
+
+        match exact {
+            Some(true) => Ok(()),
+            _ => Err(Error::Config(
+                "The last stage must compare whole files (e.g. 'hash')"
+                    .to_string()
+            )),
+        }
+    }
+
+    fn remove_unique_by_stage(
+        &self, entries : Vec<FSEntry>, heuristic : &dyn Heuristic,
+        max_compare : usize
+    ) -> Result<Vec<FSEntry>>
+    {
+        let name = heuristic.name();
+        let cost : u64 = entries.iter().map( |x| heuristic.cost(x.size) ).sum();
+
+        info!("Grouping entries by {} (reading about {} bytes)", name, cost);
+
+        let result = if max_compare > 1 {
+            remove_unique_entries_by_comparison(
+                entries,
+                self.cmp_dev(),
+                heuristic,
+                &self.reader,
+                max_compare,
+                &self.progress,
+                &self.interrupt,
+                self.scan.abort_on_error,
+            )
+        }
+        else {
+            remove_unique_entries_by_stage(
+                entries,
+                self.cmp_dev(),
+                heuristic,
+                &self.reader,
+                &self.progress,
+                &self.interrupt,
+                self.scan.abort_on_error,
+            )
+        };
+
+        match &result {
+            Ok(r)  => { log_possible_duplicates(r, &name); },
+            Err(e) => { warn!("Grouping by {} failed: {}", name, e); },
+        }
+
+        result
+    }
+
+    pub fn remove_unique_files(&self, entries : Vec<FSEntry>)
+        -> Result<Vec<FSEntry>>
+    {
+        self.check_stages()?;
+
+        info!("Grouping entries by size");
+        let mut result = remove_unique_entries_by_heuristic(
+            entries, self.cmp_dev()
+        );
+        log_possible_duplicates(&result, "file size");
+
+        // Attributes are cheap to compare and shrink groups before any
+        // content is read
+        for attr in self.same.iter() {
+            result = self.remove_unique_by_stage(result, attr, 0)?;
+
+            if self.interrupt.is_set() {
+                return Ok(result);
+            }
+        }
+
+        for stage in self.stages.iter() {
+            // Byte stages are disabled by `n_read` 0, samples also by
+            // `n_samples` 0
+            if (self.n_read == 0) && stage.is_sample() {
+                continue;
+            }
+
+            if (self.n_samples == 0) && matches!(stage, Stage::Samples) {
+                continue;
+            }
+
+            let heuristics : Vec<Rc<dyn Heuristic>> = match stage {
+                Stage::BlockHash => {
+                    let max_size = result.iter().map( |x| { x.size } ).max();
+
+                    block_hash_rounds(self.hash, max_size.unwrap_or(0))
+                        .into_iter()
+                        .map( |x| { Rc::new(x) as Rc<dyn Heuristic> } )
+                        .collect()
+                },
+                _ => vec![
+                    stage.heuristic(self.n_read, self.n_samples, self.hash)
+                ],
+            };
+
+            // Verification compares every group anyway
+            let verify  = self.verifies(stage);
+            let compare = match stage {
+                Stage::Hash if ! verify => self.compare,
+                Stage::Compare          => MAX_COMPARE_FILES,
+                _                       => 0,
+            };
+
+            for heuristic in heuristics.iter() {
+                result = self.remove_unique_by_stage(
+                    result, heuristic.as_ref(), compare
+                )?;
+
+                if self.interrupt.is_set() {
+                    return Ok(result);
+                }
+            }
+
+            // Groups that cannot be compared, e.g. too large to open all of
+            // their files at once, are hashed with BLAKE3 instead
+            if verify {
+                info!("Verifying groups of {} by comparison", self.hash.name());
+
+                result = remove_unique_entries_by_comparison(
+                    result,
+                    self.cmp_dev(),
+                    &FileHash(HashAlgorithm::Blake3),
+                    &self.reader,
+                    MAX_COMPARE_FILES,
+                    &self.progress,
+                    &self.interrupt,
+                    self.scan.abort_on_error,
+                )?;
+                log_possible_duplicates(&result, "comparison");
+
+                if self.interrupt.is_set() {
+                    return Ok(result);
+                }
+            }
+        }
+
+        Ok(result)
+    }
+
+    pub fn find_duplicates(&self, entries : Vec<FSEntry>)
+        -> Result<Vec<Vec<FSEntry>>>
+    {
+        let entries = self.remove_unique_files(entries)?;
+
+        let duplicate_groups = group_by_heuristic(entries, self.cmp_dev());
+        let duplicate_groups = filter_groups(duplicate_groups, &self.filter);
+        let mut duplicate_groups =
+            apply_metadata_policy(duplicate_groups, self.metadata);
+
+        duplicate_groups.sort_by( |a, b| a[0].size.cmp(&b[0].size) );
+
+        Ok(duplicate_groups)
+    }
+
+    pub fn deduplicate(&self, duplicate_groups : &[Vec<FSEntry>])
+        -> Result<DedupSummary>
+    {
+        let modifies = (! self.dry_run) && (self.action != DedupAction::Print);
+
+        let journal = match &self.journal {
+            Some(path) if modifies => Some(Journal::open(path)?),
+            _                      => None,
+        };
+
+        let summary = deduplicate(
+            duplicate_groups, self.action, self.scan.abort_on_error,
+            self.dry_run, &self.progress, &self.interrupt, journal.as_ref(),
+            &mut **self.plan.borrow_mut()
+        )?;
+
+        // Only groups that were completely deduplicated are reconciled
+        if (self.metadata == MetadataPolicy::Reconcile) && ! self.dry_run {
+            let done_groups : Vec<&[FSEntry]> = summary.done_groups.iter()
+                .map( |x| { duplicate_groups[*x].as_slice() } )
+                .collect();
+
+            reconcile_metadata(
+                &done_groups, self.scan.abort_on_error, &self.progress,
+                journal.as_ref()
+            )?;
+        }
+
+        Ok(summary)
+    }
+
+    // Writes a shell script that performs `deduplicate` later
+    pub fn emit_script(&self, duplicate_groups : &[Vec<FSEntry>], path : &str)
+        -> Result<()>
+    {
+        if self.action == DedupAction::Print {
+            return Err(Error::Config(
+                "A script requires action 'hardlink' or 'symlink'".to_string()
+            ));
+        }
+
+        if self.metadata == MetadataPolicy::Reconcile {
+            return Err(Error::Config(
+                "A script cannot reconcile metadata".to_string()
+            ));
+        }
+
+        let action = self.action;
+        let result = File::create(path).and_then(
+            |mut file| { write_script(&mut file, duplicate_groups, action) }
+        );
+
+        result.map_err( |e| { Error::output(path, e) } )
+    }
+
+    pub fn search(&self) -> Result<DedupReport> {
+        self.check_stages()?;
+
+        let entries = self.scan.collect(&self.progress, &self.interrupt)?;
+        let mut stats = DuplicateStats::new(&entries);
+
+        info!(
+            "Found {} entries (inodes: {})", stats.n_files, stats.n_inodes
+        );
+
+        let groups = self.find_duplicates(entries)?;
+        stats.count_groups(&groups);
+
+        Ok(DedupReport {
+            groups, stats,
+            complete : ! self.interrupt.is_set(),
+            dedup    : DedupSummary::default(),
+        })
+    }
+
+    // Groups of an incomplete search are never deduplicated
+    pub fn run(&self) -> Result<DedupReport> {
+        let mut report = self.search()?;
+
+        if report.complete {
+            report.dedup = self.deduplicate(&report.groups)?;
+        }
+
+        Ok(report)
+    }
+}
@@ -0,0 +1,83 @@
+use std::io::{self, Read};
+
+use utils::reader::{Reader, ReadFile};
+
+const BLOCK_SIZE : usize = 64 * 1024;
+
+// All files of a compared group are open at once, larger groups are hashed
+pub const MAX_COMPARE_FILES : usize = 64;
+
+// Fills `buf` unless the end of file is reached first
+fn read_block(f : &mut ReadFile, buf : &mut [u8]) -> io::Result<usize> {
+    let mut result : usize = 0;
+
+    while result < buf.len() {
+        match f.read(&mut buf[result..]) {
+            Ok(0)  => break,
+            Ok(n)  => { result += n; },
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(result)
+}
+
+// Splits files into classes of identical contents by reading them side by
+// side. Reading stops as soon as every file is known to differ from all
+// others. Returns the class index of each file.
+pub fn compare_files(paths : &[&str], reader : &Reader)
+    -> io::Result<Vec<usize>>
+{
+    let mut files : Vec<ReadFile> = paths.iter()
+        .map( |x| { reader.open(x) } )
+        .collect::<io::Result<Vec<ReadFile>>>()?;
+
+    let mut buffers : Vec<Vec<u8>> = vec![ vec![ 0; BLOCK_SIZE ]; paths.len() ];
+    let mut lengths : Vec<usize>   = vec![ 0; paths.len() ];
+
+    let mut active   : Vec<Vec<usize>> = vec![ (0..paths.len()).collect() ];
+    let mut finished : Vec<Vec<usize>> = Vec::new();
+
+    while ! active.is_empty() {
+        for idx in active.iter().flatten() {
+            lengths[*idx] = read_block(&mut files[*idx], &mut buffers[*idx])?;
+        }
+
+        let block = | idx : usize | { &buffers[idx][..lengths[idx]] };
+        let mut next : Vec<Vec<usize>> = Vec::new();
+
+        for class in active.into_iter() {
+            let mut parts : Vec<Vec<usize>> = Vec::new();
+
+            for idx in class.into_iter() {
+                match parts.iter_mut().find( |x| { block(x[0]) == block(idx) } )
+                {
+                    Some(part) => part.push(idx),
+                    None       => parts.push(vec![ idx ]),
+                }
+            }
+
+            for part in parts.into_iter() {
+                if (part.len() == 1) || (lengths[part[0]] == 0) {
+                    finished.push(part);
+                }
+                else {
+                    next.push(part);
+                }
+            }
+        }
+
+        active = next;
+    }
+
+    let mut result : Vec<usize> = vec![ 0; paths.len() ];
+
+    for (class_idx, class) in finished.iter().enumerate() {
+        for idx in class.iter() {
+            result[*idx] = class_idx;
+        }
+    }
+
+    Ok(result)
+}
@@ -1,21 +1,59 @@
-use std::io;
+use std::cell::RefCell;
 use std::fs;
+use std::io::Write;
 
 use args::DedupAction;
-use fs_entry::FSEntry;
-use utils::progress::get_progress_bar;
+use error::{Error, Result};
+use fs_entry::{FSEntry, FSPath};
+use dups::journal::{Journal, JournalRecord};
+use dups::script::shell_quote;
+use utils::interrupt::Interrupt;
+use utils::progress::{DedupOp, ProgressEvent, ProgressSink};
 use utils::path::calculate_relative_path;
 
-pub struct DedupState {
+pub struct DedupState<'a> {
     pub action         : DedupAction,
     pub abort_on_error : bool,
     pub dry_run        : bool,
+    pub progress       : &'a dyn ProgressSink,
+    pub interrupt      : &'a Interrupt,
+    pub journal        : Option<&'a Journal>,
+    pub plan           : RefCell<&'a mut dyn Write>,
+}
+
+// Groups skipped after an error in sloppy mode are not in `done_groups`
+#[derive(Clone)]
+#[derive(Debug)]
+#[derive(Default)]
+#[derive(PartialEq)]
+pub struct DedupSummary {
+    pub n_groups     : usize,
+    pub done_groups  : Vec<usize>,
+    pub n_done_files : usize,
+    pub interrupted  : bool,
+}
+
+impl<'a> DedupState<'a> {
+
+    // Only operations that succeeded are reported
+    fn report(&self, op : DedupOp, src : &str, dst : &str) {
+        self.progress.event(
+            &ProgressEvent::Dedup { op, src, dst, dry_run : self.dry_run }
+        );
+    }
+
+    // A plan that cannot be written does not change any file
+    fn plan(&self, line : &str) {
+        if let Err(e) = writeln!(self.plan.borrow_mut(), "{}", line) {
+            warn!("Failed to write dry run plan: {}", e);
+        }
+    }
 }
 
 macro_rules! handle_dry_run {
     ( $e:expr, $state:expr, $($msg:expr),+ ) => {
         if $state.dry_run {
-            println!($($msg),+);
+            $state.plan(&format!($($msg),+));
         }
         else {
             debug!($($msg),+);
@@ -24,13 +62,18 @@ macro_rules! handle_dry_run {
     };
 }
 
-fn find_group_leader_index(group : &[FSEntry]) -> usize
+pub fn find_group_leader_index(group : &[FSEntry]) -> usize
 {
-    let lowest_priority =
-        group.iter().map(|entry| entry.priority).min().unwrap();
+    let has_protected = group.iter().any( |entry| entry.is_protected() );
+
+    let lowest_priority = group.iter()
+        .filter(|entry| { (! has_protected) || entry.is_protected() })
+        .map(   |entry| { entry.priority })
+        .min().unwrap();
 
     let result = group.iter()
         .enumerate()
+        .filter(|(  _, entry)| { (! has_protected) || entry.is_protected() })
         .filter(|(  _, entry)| { entry.priority == lowest_priority })
         .map(   |(idx, entry)| { (idx, entry.paths.len()) })
         .max_by(|a, b| { a.1.cmp(&b.1) });
@@ -38,28 +81,64 @@ fn find_group_leader_index(group : &[FSEntry]) -> usize
     result.unwrap().0
 }
 
+fn link_op(action : DedupAction) -> DedupOp {
+    match action {
+        DedupAction::Symlink => DedupOp::Symlink,
+        _                    => DedupOp::Hardlink,
+    }
+}
+
+// A journal that cannot be written makes the run impossible to undo, so this
+// error is not skipped even in sloppy mode.
+fn journal_file(path : &str, leader : &str, state : &DedupState)
+    -> Result<bool>
+{
+    let journal = match state.journal {
+        Some(x) if ! state.dry_run => x,
+        _                          => return Ok(true),
+    };
+
+    let meta = sloppy_unwrap_or!(
+        fs::symlink_metadata(path).map_err(
+            |e| { Error::dedup("stat", path, e) }
+        ),
+        state, return Ok(false)
+    );
+
+    let record = JournalRecord::new(link_op(state.action), path, leader, &meta);
+    verbose_question_mark!(journal.record(&record), state);
+
+    Ok(true)
+}
+
 fn deduplicate_file(src : &str, dst : &str, state : &DedupState)
-    -> io::Result<()>
+    -> Result<()>
 {
     match state.action {
         DedupAction::Symlink  => {
             let rel_src = calculate_relative_path(src, dst);
             handle_dry_run!(
                 verbose_question_mark!(
-                    std::os::unix::fs::symlink(&rel_src, dst), state,
-                    format!("Failed to make symlink: {} -> {}", dst, rel_src)
+                    std::os::unix::fs::symlink(&rel_src, dst).map_err(
+                        |e| { Error::dedup("make symlink", dst, e) }
+                    ),
+                    state
                 ),
-                state, "  ln -s '{}' '{}'", rel_src, dst
+                state, "  ln -s {} {}", shell_quote(&rel_src), shell_quote(dst)
             );
+            state.report(DedupOp::Symlink, &rel_src, dst);
         },
         DedupAction::Hardlink => {
             handle_dry_run!(
                 verbose_question_mark!(
-                    fs::hard_link(&src, dst), state,
-                    format!("Failed to make hardlink: {} -> {}", dst, src)
+                    fs::hard_link(src, dst).map_err(
+                        |e| { Error::dedup("make hardlink", dst, e) }
+                    ),
+                    state
                 ),
-                state, "  ln '{}' '{}'", src, dst
+                state, "  ln {} {}", shell_quote(src), shell_quote(dst)
             );
+            state.report(DedupOp::Hardlink, src, dst);
         },
         _ => {},
     };
@@ -67,11 +146,12 @@ fn deduplicate_file(src : &str, dst : &str, state : &DedupState)
     Ok(())
 }
 
-fn deduplicate_group(group : &[FSEntry], state : &DedupState)
-    -> io::Result<()>
+fn deduplicate_group(
+    group : &[FSEntry], state : &DedupState, summary : &mut DedupSummary
+) -> Result<()>
 {
     let leader_index = find_group_leader_index(group);
-    let leader_path  = &group[leader_index].paths[0];
+    let leader_path  = &group[leader_index].leader_path().path;
 
     for (idx, entry) in group.iter().enumerate() {
 
@@ -79,57 +159,109 @@ fn deduplicate_group(group : &[FSEntry], state : &DedupState)
             continue;
         }
 
-        for path in entry.paths.iter() {
+        for FSPath { path, protected, .. } in entry.paths.iter() {
+            // Checked only between files, so that a file is never left
+            // removed without its link
+            if state.interrupt.is_set() {
+                summary.interrupted = true;
+                return Ok(());
+            }
+
+            if *protected {
+                debug!("Skipping protected file {}", path);
+                continue;
+            }
+
+            if ! journal_file(path, leader_path, state)? {
+                continue;
+            }
+
             handle_dry_run!(
                 sloppy_unwrap_or_continue!(
-                    fs::remove_file(path), state,
-                    format!("Failed to remove file {}", path)
+                    fs::remove_file(path).map_err(
+                        |e| { Error::dedup("remove file", path, e) }
+                    ),
+                    state
                 ),
-                state, "  rm '{}'", path
+                state, "  rm {}", shell_quote(path)
             );
+            state.report(DedupOp::Remove, leader_path, path);
 
             deduplicate_file(leader_path, path, state)?;
+            summary.n_done_files += 1;
         }
     }
 
     Ok(())
 }
 
+// Dry run plan is written to `plan` instead of performing the operations
+#[allow(clippy::too_many_arguments)]
 pub fn deduplicate(
     duplicate_groups : &[Vec<FSEntry>],
     action           : DedupAction,
     abort_on_error   : bool,
     dry_run          : bool,
-    verbose          : bool,
-) -> io::Result<()>
+    progress         : &dyn ProgressSink,
+    interrupt        : &Interrupt,
+    journal          : Option<&Journal>,
+    plan             : &mut dyn Write,
+) -> Result<DedupSummary>
 {
+    let mut summary = DedupSummary {
+        n_groups : duplicate_groups.len(), ..Default::default()
+    };
+
     if (action == DedupAction::Print) || duplicate_groups.is_empty() {
-        return Ok(());
+        return Ok(summary);
     }
 
-    let state = DedupState{ action, abort_on_error, dry_run };
-    let pbar  = get_progress_bar(
-        duplicate_groups.len() as u64, "Deduplicating", verbose && (! dry_run)
-    );
+    let state = DedupState{
+        action, abort_on_error, dry_run, progress, interrupt, journal,
+        plan : RefCell::new(plan),
+    };
+    let title = "Deduplicating";
 
+    // The plan usually goes to stdout, a progress bar would garble it
     if dry_run {
-        println!("Dry run:")
+        state.plan("Dry run:");
+    }
+    else {
+        progress.event(&ProgressEvent::StageStart {
+            name : title, total : duplicate_groups.len() as u64
+        });
     }
 
     for (group_index,group) in duplicate_groups.iter().enumerate() {
         if dry_run {
-            println!("[{}]", group_index);
+            state.plan(&format!("[{}]", group_index));
         }
 
-        sloppy_unwrap_or_continue!(
-            deduplicate_group(group, &state), state, ""
+        quiet_unwrap_or_continue!(
+            deduplicate_group(group, &state, &mut summary), state
         );
 
-        if let Some(x) = pbar.as_ref().as_mut() { x.inc(1) };
+        if summary.interrupted {
+            break;
+        }
+
+        summary.done_groups.push(group_index);
+
+        if ! dry_run {
+            progress.event(
+                &ProgressEvent::StageTick { name : title, path : None }
+            );
+        }
     }
 
-    if let Some(x) = pbar { x.abandon() }
+    if ! dry_run {
+        progress.event(&ProgressEvent::StageFinish { name : title });
+    }
 
-    Ok(())
+    if let Some(journal) = journal {
+        journal.sync()?;
+    }
+
+    Ok(summary)
 }
 
@@ -1,65 +1,122 @@
 use std::cmp::Ordering;
-use std::io;
-use indicatif::ProgressBar;
 
-use fs_entry::{FSEntry, Heuristic};
-use dups::heuristics::HeuristicFn;
-use utils::progress::get_progress_bar;
+use error::{Error, Result};
+use fs_entry::{FSEntry, HKey};
+use dups::heuristics::{Heuristic, small_file_key};
+use utils::interrupt::Interrupt;
+use utils::fiemap::first_physical_offset;
+use utils::reader::{ReadOrder, Reader};
+use utils::progress::{ProgressEvent, ProgressSink};
 
-pub struct Evaluator {
-    pub func            : Box<HeuristicFn>,
-    pub verbose         : bool,
+pub struct Evaluator<'a> {
+    pub heuristic       : &'a dyn Heuristic,
+    pub reader          : &'a Reader,
+    pub title           : String,
     pub abort_on_error  : bool,
-    pub pbar            : Option<ProgressBar>,
+    pub progress        : &'a dyn ProgressSink,
+    pub interrupt       : &'a Interrupt,
 }
 
-impl Evaluator {
+impl<'a> Evaluator<'a> {
 
     pub fn new(
-        size : usize, title : &str, verbose : bool, abort_on_error : bool,
-        func : Box<HeuristicFn>
+        size : usize, title : &str, progress : &'a dyn ProgressSink,
+        interrupt : &'a Interrupt, abort_on_error : bool,
+        heuristic : &'a dyn Heuristic, reader : &'a Reader
     ) -> Self
     {
-        let pbar = get_progress_bar(size as u64, title, verbose);
-        Self{ func, verbose, abort_on_error, pbar }
+        progress.event(
+            &ProgressEvent::StageStart { name : title, total : size as u64 }
+        );
+
+        Self{
+            heuristic, reader, title : title.to_string(), abort_on_error,
+            progress, interrupt
+        }
     }
 
-    fn eval(&mut self, entry : &FSEntry) -> io::Result<Heuristic> {
-        let result = (*self.func)(entry);
-        self.tick();
+    fn eval(&mut self, entry : &mut FSEntry) -> Result<HKey> {
+        let small = self.reader.is_small(entry.size)
+            && self.heuristic.reads_raw_contents();
+
+        let result = if small {
+            small_file_key(entry, self.reader)
+        }
+        else {
+            self.heuristic.key(entry, self.reader)
+        };
+
+        let result = result.map_err(
+            |e| { Error::heuristic(&self.title, &entry.paths[0].path, e) }
+        );
+        self.tick(&entry.paths[0].path);
 
         result
     }
 
-    pub fn tick(&mut self) {
-        if let Some(pb) = &self.pbar {
-            pb.inc(1);
-        }
+    pub fn tick(&mut self, path : &str) {
+        self.progress.event(&ProgressEvent::StageTick {
+            name : &self.title, path : Some(path)
+        });
     }
 
     pub fn finish(&mut self) {
-        if let Some(pb) = &self.pbar {
-            pb.abandon();
+        self.progress.event(
+            &ProgressEvent::StageFinish { name : &self.title }
+        );
+    }
+
+    // Files without extents, or on filesystems without FIEMAP, are read
+    // last in inode order. Offsets are queried once per entry.
+    fn sort_by_physical_offset(entries : &mut [FSEntry]) {
+        for entry in entries.iter_mut().filter( |x| { x.offset.is_none() } ) {
+            let path   = &entry.paths[0].path;
+            let offset = first_physical_offset(path).unwrap_or_else(
+                |e| {
+                    debug!("Failed to get extents of {}: {}", path, e);
+                    None
+                }
+            );
+
+            entry.offset = Some(offset.unwrap_or(u64::MAX));
         }
+
+        entries.sort_by_key( |x| { (x.dev, x.offset, x.inode) } );
     }
 
-    pub fn evaluate(&mut self, entries : &mut Vec<FSEntry>) -> io::Result<()> {
+    pub fn evaluate(&mut self, entries : &mut [FSEntry]) -> Result<()> {
         // Sorting by inode helps speed up file reading on HDD for some FS
-        entries.sort_by(
-            | a, b | {
-                match a.dev.cmp(&b.dev) {
-                    Ordering::Equal => a.inode.cmp(&b.inode),
-                    other           => other,
+        if self.reader.order == ReadOrder::Physical {
+            Self::sort_by_physical_offset(entries);
+        }
+        else {
+            entries.sort_by(
+                | a, b | {
+                    match a.dev.cmp(&b.dev) {
+                        Ordering::Equal => a.inode.cmp(&b.inode),
+                        other           => other,
+                    }
                 }
+            );
+        }
+
+        // Values are assigned only once every entry is evaluated, so that an
+        // interrupted stage leaves entries grouped by the previous heuristic
+        let mut values : Vec<(usize, HKey)> =
+            Vec::with_capacity(entries.len());
+
+        for (idx, entry) in entries.iter_mut().enumerate() {
+            if self.interrupt.is_set() {
+                self.finish();
+                return Ok(());
             }
-        );
 
-        for entry in entries.iter_mut() {
-            let value = sloppy_unwrap_or_continue!(
-                self.eval(&entry), self, ""
-            );
+            let value = sloppy_unwrap_or_continue!(self.eval(entry), self);
+            values.push((idx, value));
+        }
 
-            entry.hvalue = value;
+        for (idx, value) in values.into_iter() {
+            entries[idx].hvalue = Some(value);
         }
 
         self.finish();
@@ -0,0 +1,147 @@
+use std::collections::BTreeSet;
+
+use fs_entry::{FSEntry, Priority};
+
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub enum RootSpan {
+    Any, CrossRoot, WithinRoot,
+}
+
+pub struct GroupFilter {
+    pub span       : RootSpan,
+    pub with_roots : Vec<Priority>,
+}
+
+impl GroupFilter {
+
+    pub fn new(span : RootSpan, with_roots : Vec<Priority>) -> Self {
+        GroupFilter { span, with_roots }
+    }
+
+    pub fn is_trivial(&self) -> bool {
+        (self.span == RootSpan::Any) && self.with_roots.is_empty()
+    }
+
+    pub fn accepts(&self, group : &[FSEntry]) -> bool {
+        let roots = find_group_roots(group);
+
+        let span_ok = match self.span {
+            RootSpan::Any        => true,
+            RootSpan::CrossRoot  => roots.len() > 1,
+            RootSpan::WithinRoot => roots.len() == 1,
+        };
+
+        span_ok && self.with_roots.iter().all( |x| { roots.contains(x) } )
+    }
+}
+
+pub fn find_group_roots(group : &[FSEntry]) -> BTreeSet<Priority>
+{
+    group.iter()
+        .flat_map(|entry| { entry.paths.iter() })
+        .map(     |path|  { path.priority })
+        .collect()
+}
+
+pub fn filter_groups(groups : Vec<Vec<FSEntry>>, filter : &GroupFilter)
+    -> Vec<Vec<FSEntry>>
+{
+    if filter.is_trivial() {
+        return groups;
+    }
+
+    groups.into_iter().filter( |x| { filter.accepts(x) } ).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fs_entry::FSPath;
+
+    fn init_test_group(roots : &[&[Priority]]) -> Vec<FSEntry>
+    {
+        let mut result : Vec<FSEntry> = Vec::new();
+
+        for (inode, entry_roots) in roots.iter().enumerate() {
+            let mut entry = FSEntry::new(
+                0, inode as u64, 0, entry_roots[0],
+                FSPath::new(format!("{}/0", inode), entry_roots[0], false)
+            );
+
+            for (idx, root) in entry_roots.iter().enumerate().skip(1) {
+                entry.add_path(
+                    FSPath::new(format!("{}/{}", inode, idx), *root, false)
+                );
+            }
+
+            result.push(entry);
+        }
+
+        result
+    }
+
+    fn init_test_groups() -> Vec<Vec<FSEntry>>
+    {
+        vec![
+            init_test_group(&[ &[ 0 ], &[ 0 ] ]),
+            init_test_group(&[ &[ 0 ], &[ 1 ] ]),
+            init_test_group(&[ &[ 1 ], &[ 1, 2 ] ]),
+            init_test_group(&[ &[ 2 ], &[ 2 ], &[ 2 ] ]),
+        ]
+    }
+
+    #[test]
+    fn test_filter_groups_trivial() {
+        let groups = init_test_groups();
+        let filter = GroupFilter::new(RootSpan::Any, Vec::new());
+
+        assert_eq!(filter_groups(groups.clone(), &filter), groups);
+    }
+
+    #[test]
+    fn test_filter_groups_cross_root() {
+        let groups = init_test_groups();
+        let filter = GroupFilter::new(RootSpan::CrossRoot, Vec::new());
+
+        assert_eq!(
+            filter_groups(groups.clone(), &filter),
+            vec![ groups[1].clone(), groups[2].clone() ]
+        );
+    }
+
+    #[test]
+    fn test_filter_groups_within_root() {
+        let groups = init_test_groups();
+        let filter = GroupFilter::new(RootSpan::WithinRoot, Vec::new());
+
+        assert_eq!(
+            filter_groups(groups.clone(), &filter),
+            vec![ groups[0].clone(), groups[3].clone() ]
+        );
+    }
+
+    #[test]
+    fn test_filter_groups_with_roots() {
+        let groups = init_test_groups();
+        let filter = GroupFilter::new(RootSpan::Any, vec![ 1, 2 ]);
+
+        assert_eq!(
+            filter_groups(groups.clone(), &filter),
+            vec![ groups[2].clone() ]
+        );
+    }
+
+    #[test]
+    fn test_filter_groups_cross_root_with_root() {
+        let groups = init_test_groups();
+        let filter = GroupFilter::new(RootSpan::CrossRoot, vec![ 0 ]);
+
+        assert_eq!(
+            filter_groups(groups.clone(), &filter),
+            vec![ groups[1].clone() ]
+        );
+    }
+}
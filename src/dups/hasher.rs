@@ -0,0 +1,118 @@
+use std::io::{self, Write};
+
+use blake3;
+use crypto_hash;
+use xxhash_rust::xxh3::Xxh3;
+
+// Digest of the bytes written so far. Hashers are written to by `io::copy`
+// and finished once.
+pub trait Hasher : Write {
+    fn finish(&mut self) -> Vec<u8>;
+}
+
+// Non-cryptographic hashes are fast, but colliding files are not unlikely
+// enough to skip verifying their groups by comparison
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub enum HashAlgorithm {
+    Md5, Sha1, Sha256, Sha512, Blake3, Xxh3,
+}
+
+struct Xxh3Hasher(Xxh3);
+
+impl HashAlgorithm {
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Md5    => "MD5",
+            HashAlgorithm::Sha1   => "SHA1",
+            HashAlgorithm::Sha256 => "SHA256",
+            HashAlgorithm::Sha512 => "SHA512",
+            HashAlgorithm::Blake3 => "BLAKE3",
+            HashAlgorithm::Xxh3   => "XXH3-128",
+        }
+    }
+
+    pub fn is_cryptographic(&self) -> bool {
+        *self != HashAlgorithm::Xxh3
+    }
+
+    pub fn hasher(&self) -> Box<dyn Hasher> {
+        let crypto = | algo | {
+            Box::new(crypto_hash::Hasher::new(algo)) as Box<dyn Hasher>
+        };
+
+        match self {
+            HashAlgorithm::Md5    => crypto(crypto_hash::Algorithm::MD5),
+            HashAlgorithm::Sha1   => crypto(crypto_hash::Algorithm::SHA1),
+            HashAlgorithm::Sha256 => crypto(crypto_hash::Algorithm::SHA256),
+            HashAlgorithm::Sha512 => crypto(crypto_hash::Algorithm::SHA512),
+            HashAlgorithm::Blake3 => Box::new(blake3::Hasher::new()),
+            HashAlgorithm::Xxh3   => Box::new(Xxh3Hasher(Xxh3::new())),
+        }
+    }
+}
+
+impl Hasher for crypto_hash::Hasher {
+    fn finish(&mut self) -> Vec<u8> {
+        crypto_hash::Hasher::finish(self)
+    }
+}
+
+impl Hasher for blake3::Hasher {
+    fn finish(&mut self) -> Vec<u8> {
+        self.finalize().as_bytes().to_vec()
+    }
+}
+
+impl Write for Xxh3Hasher {
+    fn write(&mut self, buf : &[u8]) -> io::Result<usize> {
+        self.0.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Hasher for Xxh3Hasher {
+    fn finish(&mut self) -> Vec<u8> {
+        self.0.digest128().to_be_bytes().to_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn digest(algo : HashAlgorithm, data : &[u8]) -> Vec<u8> {
+        let mut hasher = algo.hasher();
+        hasher.write_all(data).unwrap();
+        hasher.finish()
+    }
+
+    #[test]
+    fn test_digest_sizes() {
+        let sizes : Vec<usize> = [
+            HashAlgorithm::Md5, HashAlgorithm::Sha1, HashAlgorithm::Sha256,
+            HashAlgorithm::Sha512, HashAlgorithm::Blake3, HashAlgorithm::Xxh3,
+        ].iter().map( |x| { digest(*x, b"fdedup").len() } ).collect();
+
+        assert_eq!(sizes, vec![ 16, 20, 32, 64, 32, 16 ]);
+    }
+
+    #[test]
+    fn test_digest_chunks() {
+        for algo in [ HashAlgorithm::Blake3, HashAlgorithm::Xxh3 ].iter() {
+            let mut hasher = algo.hasher();
+            hasher.write_all(b"fd").unwrap();
+            hasher.write_all(b"edup").unwrap();
+
+            assert_eq!(hasher.finish(), digest(*algo, b"fdedup"));
+            assert_ne!(digest(*algo, b"fdedup"), digest(*algo, b"fdedu"));
+        }
+    }
+}
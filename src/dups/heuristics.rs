@@ -1,53 +1,487 @@
-use std::fs::File;
-use std::io::{self, Read, Seek, SeekFrom};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::rc::Rc;
 
-use crypto_hash::{Algorithm, Hasher};
-use fs_entry::{FSEntry, Heuristic};
+use dups::hasher::HashAlgorithm;
+use fs_entry::{FSEntry, HKey};
+use utils::reader::Reader;
+use utils::xattr::read_xattrs;
 
-pub type HeuristicFn = dyn Fn(&FSEntry) -> io::Result<Heuristic>;
+// A stage of the duplicate search. Entries with different keys are never
+// duplicates. Errors are reported by `Evaluator` like those of built-in
+// heuristics.
+pub trait Heuristic {
+    fn name(&self) -> String;
 
-pub fn fn_file_hash(entry : &FSEntry, algo : Algorithm)
-    -> io::Result<Heuristic>
-{
-    let mut f      = File::open(&entry.paths[0])?;
-    let mut hasher = Hasher::new(algo);
+    // Estimated number of bytes read from a file of this size
+    fn cost(&self, size : u64) -> u64;
+
+    // Equal keys mean equal contents, so the search may end with this stage.
+    // Non-cryptographic hashes are not exact.
+    fn is_exact(&self) -> bool {
+        false
+    }
+
+    // Each stage replaces keys of the previous one, which is only safe for
+    // keys computed from contents. Other keys are kept until the end.
+    fn depends_on_content(&self) -> bool {
+        true
+    }
 
-    io::copy(&mut f, &mut hasher)?;
+    // Keys only tell apart files of different raw contents, so that small
+    // files may be keyed by the digest of their contents instead, see
+    // `small_file_key`. Keys of normalized contents must not be replaced.
+    fn reads_raw_contents(&self) -> bool {
+        false
+    }
 
-    Ok(Heuristic::Hash(hasher.finish()))
+    // Files are opened through `reader`, which applies the I/O policy
+    fn key(&self, entry : &FSEntry, reader : &Reader) -> io::Result<HKey>;
 }
 
-pub fn fn_file_size(entry : &FSEntry) -> io::Result<Heuristic> {
-    Ok(Heuristic::Size(entry.size))
+// File attributes that duplicates may be required to share
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub enum Attribute {
+    Owner, Group, Mode, XAttrs,
 }
 
-pub fn fn_file_device(entry : &FSEntry) -> io::Result<Heuristic> {
-    Ok(Heuristic::Device(entry.dev))
+// Content stages run after grouping by size, each one only on files left by
+// the previous stage. Byte stages read `n_read` bytes of each file,
+// `Samples` reads them at `n_samples` offsets spread across the file.
+// `BlockHash` hashes files block by block and regroups after each block.
+// `Compare` compares files of each group byte by byte.
+#[derive(Clone)]
+pub enum Stage {
+    FirstBytes, MiddleBytes, LastBytes, Samples, Hash, BlockHash, Compare,
+    Custom(Rc<dyn Heuristic>),
 }
 
-pub fn fn_first_bytes(entry : &FSEntry, number : usize)
-    -> io::Result<Heuristic>
-{
-    let f = File::open(&entry.paths[0])?;
-    let mut result : Vec<u8> = Vec::with_capacity(number);
+pub const DEFAULT_STAGES : [Stage; 3] = [
+    Stage::FirstBytes, Stage::LastBytes, Stage::Hash
+];
+
+pub struct FirstBytes(pub usize);
+pub struct MiddleBytes(pub usize);
+pub struct LastBytes(pub usize);
+
+// Offsets depend only on the size, so files of a group sample the same places
+pub struct SampledBytes {
+    pub n_samples : usize,
+    pub n_read    : usize,
+}
+pub struct FileHash(pub HashAlgorithm);
+
+// Files are compared directly by the search, the key only groups files that
+// cannot be compared, e.g. of groups too large to open at once
+pub struct Comparison;
+
+// Hash of bytes `start..end` chained to the key of the previous block, so
+// that the last block's key covers the whole file
+pub struct BlockHash {
+    pub algo  : HashAlgorithm,
+    pub start : u64,
+    pub end   : u64,
+}
+
+const FIRST_BLOCK_SIZE : u64 = 64 * 1024;
+const MAX_BLOCK_SIZE   : u64 = 64 * 1024 * 1024;
+
+impl Stage {
+
+    pub fn is_sample(&self) -> bool {
+        matches!(
+            self,
+            Stage::FirstBytes | Stage::MiddleBytes | Stage::LastBytes
+                | Stage::Samples
+        )
+    }
+
+    pub fn heuristic(
+        &self, n_read : usize, n_samples : usize, algo : HashAlgorithm
+    ) -> Rc<dyn Heuristic>
+    {
+        match self {
+            Stage::FirstBytes  => Rc::new(FirstBytes(n_read)),
+            Stage::MiddleBytes => Rc::new(MiddleBytes(n_read)),
+            Stage::LastBytes   => Rc::new(LastBytes(n_read)),
+            Stage::Samples     => Rc::new(SampledBytes { n_samples, n_read }),
+            Stage::Hash        => Rc::new(FileHash(algo)),
+            Stage::BlockHash   => Rc::new(
+                BlockHash { algo, start : 0, end : u64::MAX }
+            ),
+            Stage::Compare     => Rc::new(Comparison),
+            Stage::Custom(x)   => x.clone(),
+        }
+    }
+}
 
-    f.take(number as u64).read_to_end(&mut result)?;
+// Small files are read whole by the first stage that reads raw contents. The
+// digest of their contents is the key of that stage and of all later ones,
+// which is exact and saves opening them again. Sizes within a group are
+// equal, so either all or none of its files are small. Larger files are
+// opened by each stage, keeping them open would run out of descriptors.
+pub fn small_file_key(entry : &mut FSEntry, reader : &Reader)
+    -> io::Result<HKey>
+{
+    if entry.digest.is_none() {
+        entry.digest = Some(FileHash(reader.digest).key(entry, reader)?);
+    }
 
-    Ok(Heuristic::Bytes(result))
+    Ok(entry.digest.clone().unwrap_or_default())
 }
 
-pub fn fn_last_bytes(entry : &FSEntry, number : usize)
-    -> io::Result<Heuristic>
+// Blocks grow four times each round, the last one extends to the end of
+// files of any size
+pub fn block_hash_rounds(algo : HashAlgorithm, max_size : u64)
+    -> Vec<BlockHash>
 {
-    let mut f = File::open(&entry.paths[0])?;
+    let mut result : Vec<BlockHash> = Vec::new();
+    let mut start  : u64 = 0;
+    let mut size   : u64 = FIRST_BLOCK_SIZE;
+
+    while start + size < max_size {
+        result.push(BlockHash { algo, start, end : start + size });
+
+        start += size;
+        size   = (size * 4).min(MAX_BLOCK_SIZE);
+    }
+
+    result.push(BlockHash { algo, start, end : u64::MAX });
+    result
+}
 
-    if entry.size > (number as u64) {
-        f.seek(SeekFrom::End(-(number as i64)))?;
+fn xattrs_key(path : &str) -> io::Result<HKey> {
+    let mut result : Vec<u8> = Vec::new();
+
+    for (name, value) in read_xattrs(Path::new(path))?.iter() {
+        result.extend_from_slice(name);
+        result.push(0);
+        result.extend_from_slice(&(value.len() as u64).to_be_bytes());
+        result.extend_from_slice(value);
     }
 
-    let mut result : Vec<u8> = Vec::with_capacity(number);
-    f.read_to_end(&mut result)?;
+    Ok(HKey(result))
+}
 
-    Ok(Heuristic::Bytes(result))
+impl Heuristic for Attribute {
+
+    fn name(&self) -> String {
+        match self {
+            Attribute::Owner  => "owner",
+            Attribute::Group  => "group",
+            Attribute::Mode   => "mode",
+            Attribute::XAttrs => "xattrs",
+        }.to_string()
+    }
+
+    fn cost(&self, _size : u64) -> u64 {
+        0
+    }
+
+    fn depends_on_content(&self) -> bool {
+        false
+    }
+
+    // Only permission bits of the mode, the file type is the same for all
+    // entries
+    fn key(&self, entry : &FSEntry, _reader : &Reader) -> io::Result<HKey> {
+        match self {
+            Attribute::Owner  => Ok(HKey::from(entry.meta.uid)),
+            Attribute::Group  => Ok(HKey::from(entry.meta.gid)),
+            Attribute::Mode   => Ok(HKey::from(entry.meta.mode & 0o7777)),
+            Attribute::XAttrs => xattrs_key(&entry.paths[0].path),
+        }
+    }
+}
+
+impl Heuristic for FirstBytes {
+
+    fn name(&self) -> String {
+        "first bytes".to_string()
+    }
+
+    fn cost(&self, size : u64) -> u64 {
+        size.min(self.0 as u64)
+    }
+
+    fn reads_raw_contents(&self) -> bool {
+        true
+    }
+
+    fn key(&self, entry : &FSEntry, reader : &Reader) -> io::Result<HKey> {
+        let f = reader.open(&entry.paths[0].path)?;
+        let mut result : Vec<u8> = Vec::with_capacity(self.0);
+
+        f.take(self.0 as u64).read_to_end(&mut result)?;
+
+        Ok(HKey(result))
+    }
+}
+
+impl Heuristic for MiddleBytes {
+
+    fn name(&self) -> String {
+        "middle bytes".to_string()
+    }
+
+    fn cost(&self, size : u64) -> u64 {
+        size.min(self.0 as u64)
+    }
+
+    fn reads_raw_contents(&self) -> bool {
+        true
+    }
+
+    fn key(&self, entry : &FSEntry, reader : &Reader) -> io::Result<HKey> {
+        let number = self.0 as u64;
+        let mut f  = reader.open(&entry.paths[0].path)?;
+
+        if entry.size > number {
+            f.seek(SeekFrom::Start((entry.size - number) / 2))?;
+        }
+
+        let mut result : Vec<u8> = Vec::with_capacity(self.0);
+        f.take(number).read_to_end(&mut result)?;
+
+        Ok(HKey(result))
+    }
+}
+
+impl Heuristic for LastBytes {
+
+    fn name(&self) -> String {
+        "last bytes".to_string()
+    }
+
+    fn cost(&self, size : u64) -> u64 {
+        size.min(self.0 as u64)
+    }
+
+    fn reads_raw_contents(&self) -> bool {
+        true
+    }
+
+    fn key(&self, entry : &FSEntry, reader : &Reader) -> io::Result<HKey> {
+        let mut f = reader.open(&entry.paths[0].path)?;
+
+        if entry.size > (self.0 as u64) {
+            f.seek(SeekFrom::End(-(self.0 as i64)))?;
+        }
+
+        let mut result : Vec<u8> = Vec::with_capacity(self.0);
+        f.read_to_end(&mut result)?;
+
+        Ok(HKey(result))
+    }
 }
 
+impl SampledBytes {
+
+    // Samples of small files would overlap, the whole file is read instead
+    pub fn offsets(&self, size : u64) -> Vec<u64> {
+        let number = self.n_read as u64;
+        let n_samples = self.n_samples as u64;
+
+        if n_samples == 0 {
+            return Vec::new();
+        }
+
+        if size <= number.saturating_mul(n_samples) {
+            return vec![ 0 ];
+        }
+
+        if n_samples == 1 {
+            return vec![ (size - number) / 2 ];
+        }
+
+        (0..n_samples).map(
+            |i| {
+                let span = (size - number) as u128;
+                (span * (i as u128) / ((n_samples - 1) as u128)) as u64
+            }
+        ).collect()
+    }
+}
+
+impl Heuristic for SampledBytes {
+
+    fn name(&self) -> String {
+        format!("{} samples", self.n_samples)
+    }
+
+    fn cost(&self, size : u64) -> u64 {
+        size.min((self.n_read as u64).saturating_mul(self.n_samples as u64))
+    }
+
+    fn reads_raw_contents(&self) -> bool {
+        true
+    }
+
+    fn key(&self, entry : &FSEntry, reader : &Reader) -> io::Result<HKey> {
+        let number = if entry.size <= self.cost(entry.size) {
+            entry.size
+        }
+        else {
+            self.n_read as u64
+        };
+
+        let mut f = reader.open(&entry.paths[0].path)?;
+        let mut result : Vec<u8> = Vec::new();
+
+        for offset in self.offsets(entry.size).into_iter() {
+            f.seek(SeekFrom::Start(offset))?;
+            (&mut f).take(number).read_to_end(&mut result)?;
+        }
+
+        Ok(HKey(result))
+    }
+}
+
+impl Heuristic for FileHash {
+
+    fn name(&self) -> String {
+        format!("hash ({})", self.0.name())
+    }
+
+    fn cost(&self, size : u64) -> u64 {
+        size
+    }
+
+    fn is_exact(&self) -> bool {
+        self.0.is_cryptographic()
+    }
+
+    fn reads_raw_contents(&self) -> bool {
+        true
+    }
+
+    fn key(&self, entry : &FSEntry, reader : &Reader) -> io::Result<HKey> {
+        let mut f      = reader.open(&entry.paths[0].path)?;
+        let mut hasher = self.0.hasher();
+
+        io::copy(&mut f, &mut hasher)?;
+
+        Ok(HKey(hasher.finish()))
+    }
+}
+
+impl Heuristic for Comparison {
+
+    fn name(&self) -> String {
+        "comparison".to_string()
+    }
+
+    fn cost(&self, size : u64) -> u64 {
+        size
+    }
+
+    fn is_exact(&self) -> bool {
+        true
+    }
+
+    fn reads_raw_contents(&self) -> bool {
+        true
+    }
+
+    fn key(&self, entry : &FSEntry, reader : &Reader) -> io::Result<HKey> {
+        FileHash(HashAlgorithm::Blake3).key(entry, reader)
+    }
+}
+
+impl Heuristic for BlockHash {
+
+    fn name(&self) -> String {
+        if self.end == u64::MAX {
+            format!(
+                "block hash ({}, from byte {})", self.algo.name(), self.start
+            )
+        }
+        else {
+            format!(
+                "block hash ({}, bytes {}-{})",
+                self.algo.name(), self.start, self.end
+            )
+        }
+    }
+
+    fn cost(&self, size : u64) -> u64 {
+        size.min(self.end).saturating_sub(self.start)
+    }
+
+    fn is_exact(&self) -> bool {
+        (self.end == u64::MAX) && self.algo.is_cryptographic()
+    }
+
+    // Files that end before this block keep the key of the previous one
+    fn reads_raw_contents(&self) -> bool {
+        true
+    }
+
+    fn key(&self, entry : &FSEntry, reader : &Reader) -> io::Result<HKey> {
+        if let Some(prev) = &entry.hvalue {
+            if (self.start > 0) && (entry.size <= self.start) {
+                return Ok(prev.clone());
+            }
+        }
+
+        let mut f      = reader.open(&entry.paths[0].path)?;
+        let mut hasher = self.algo.hasher();
+
+        if self.start > 0 {
+            hasher.write_all(&entry.hvalue.clone().unwrap_or_default().0)?;
+            f.seek(SeekFrom::Start(self.start))?;
+        }
+
+        io::copy(&mut f.take(self.end - self.start), &mut hasher)?;
+
+        Ok(HKey(hasher.finish()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_block_hash_rounds() {
+        let algo = HashAlgorithm::Sha256;
+
+        let rounds : Vec<(u64, u64)> = block_hash_rounds(algo, 1000)
+            .iter().map( |x| { (x.start, x.end) } ).collect();
+        assert_eq!(rounds, vec![ (0, u64::MAX) ]);
+
+        let rounds : Vec<(u64, u64)> = block_hash_rounds(algo, 1 << 20)
+            .iter().map( |x| { (x.start, x.end) } ).collect();
+        assert_eq!(rounds, vec![
+            (0,            64 * 1024),
+            (64 * 1024,    320 * 1024),
+            (320 * 1024,   u64::MAX),
+        ]);
+
+        assert!(! block_hash_rounds(algo, 1 << 20)[1].is_exact());
+        assert!(block_hash_rounds(algo, 1 << 20)[2].is_exact());
+
+        assert_eq!(
+            block_hash_rounds(algo, 1 << 20)[1].name(),
+            "block hash (SHA256, bytes 65536-327680)"
+        );
+    }
+
+    #[test]
+    fn test_sample_offsets() {
+        let samples = | n_samples | { SampledBytes { n_samples, n_read : 10 } };
+
+        assert_eq!(samples(0).offsets(100), vec![]);
+        assert_eq!(samples(4).offsets(40),  vec![ 0 ]);
+        assert_eq!(samples(4).offsets(100), vec![ 0, 30, 60, 90 ]);
+        assert_eq!(samples(1).offsets(100), vec![ 45 ]);
+        assert_eq!(samples(3).offsets(u64::MAX).last(), Some(&(u64::MAX - 10)));
+
+        let huge = SampledBytes { n_samples : usize::MAX, n_read : usize::MAX };
+        assert_eq!(huge.offsets(100), vec![ 0 ]);
+        assert_eq!(huge.cost(100), 100);
+    }
+}
@@ -0,0 +1,542 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::{self, File, FileTimes, Metadata, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::prelude::*;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use error::{Error, Result};
+use dups::metadata::set_mtime;
+use fs_entry::{Dev, INode};
+use utils::path::calculate_relative_path;
+use utils::progress::{DedupOp, ProgressEvent, ProgressSink};
+
+pub const JOURNAL_HEADER : &str = "# fdedup journal v1";
+
+const UNDO_SUFFIX : &str = ".fdedup-undo";
+
+const MAX_TEMP_NAMES : usize = 100;
+
+// State of a file right before it was replaced by a link to `leader`, or
+// before its metadata was changed (action `Metadata`, `leader` is the file
+// itself)
+#[derive(Clone)]
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub struct JournalRecord {
+    pub action : DedupOp,
+    pub path   : String,
+    pub leader : String,
+    pub dev    : Dev,
+    pub inode  : INode,
+    pub mode   : u32,
+    pub uid    : u32,
+    pub gid    : u32,
+    pub atime  : (i64, i64),
+    pub mtime  : (i64, i64),
+}
+
+pub struct Journal {
+    path : String,
+    file : RefCell<File>,
+}
+
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+#[derive(Default)]
+#[derive(PartialEq)]
+pub struct UndoSummary {
+    pub n_records  : usize,
+    pub n_restored : usize,
+    pub n_skipped  : usize,
+}
+
+struct UndoState<'a> {
+    abort_on_error : bool,
+    dry_run        : bool,
+    progress       : &'a dyn ProgressSink,
+    plan           : RefCell<&'a mut dyn Write>,
+}
+
+impl<'a> UndoState<'a> {
+
+    fn plan(&self, line : &str) {
+        if let Err(e) = writeln!(self.plan.borrow_mut(), "{}", line) {
+            warn!("Failed to write dry run plan: {}", e);
+        }
+    }
+}
+
+fn escape_field(s : &str) -> String {
+    s.replace('\\', "\\\\").replace('\t', "\\t").replace('\n', "\\n")
+}
+
+fn unescape_field(s : &str) -> std::result::Result<String, String> {
+    let mut result = String::with_capacity(s.len());
+    let mut chars  = s.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('\\') => result.push('\\'),
+            Some('t')  => result.push('\t'),
+            Some('n')  => result.push('\n'),
+            _          => return Err(format!("Bad escape sequence in: {}", s)),
+        }
+    }
+
+    Ok(result)
+}
+
+fn parse_time(s : &str) -> std::result::Result<(i64, i64), String> {
+    let mut parts = s.splitn(2, '.');
+
+    let sec  = parts.next().and_then( |x| { x.parse::<i64>().ok() } );
+    let nsec = parts.next().and_then( |x| { x.parse::<i64>().ok() } );
+
+    match (sec, nsec) {
+        (Some(sec), Some(nsec)) => Ok((sec, nsec)),
+        _ => Err(format!("Cannot parse time: {}", s)),
+    }
+}
+
+fn to_system_time(time : (i64, i64)) -> SystemTime {
+    let (sec, nsec) = time;
+    let nsec = Duration::from_nanos(nsec as u64);
+
+    if sec >= 0 {
+        UNIX_EPOCH + Duration::from_secs(sec as u64) + nsec
+    }
+    else {
+        UNIX_EPOCH - Duration::from_secs(sec.unsigned_abs()) + nsec
+    }
+}
+
+impl JournalRecord {
+
+    pub fn new(action : DedupOp, path : &str, leader : &str, meta : &Metadata)
+        -> Self
+    {
+        JournalRecord {
+            action,
+            path   : path.to_string(),
+            leader : leader.to_string(),
+            dev    : meta.dev(),
+            inode  : meta.ino(),
+            mode   : meta.mode(),
+            uid    : meta.uid(),
+            gid    : meta.gid(),
+            atime  : (meta.atime(), meta.atime_nsec()),
+            mtime  : (meta.mtime(), meta.mtime_nsec()),
+        }
+    }
+
+    pub fn to_line(&self) -> String {
+        format!(
+            "{}\t{}\t{}\t{:o}\t{}\t{}\t{}.{:09}\t{}.{:09}\t{}\t{}",
+            format!("{:?}", self.action).to_lowercase(),
+            self.dev, self.inode, self.mode, self.uid, self.gid,
+            self.atime.0, self.atime.1, self.mtime.0, self.mtime.1,
+            escape_field(&self.path), escape_field(&self.leader)
+        )
+    }
+
+    pub fn from_line(line : &str) -> std::result::Result<Self, String> {
+        let fields : Vec<&str> = line.split('\t').collect();
+
+        if fields.len() != 10 {
+            return Err(format!("Expected 10 fields, found {}", fields.len()));
+        }
+
+        let action = match fields[0] {
+            "hardlink" => DedupOp::Hardlink,
+            "symlink"  => DedupOp::Symlink,
+            "metadata" => DedupOp::Metadata,
+            x          => return Err(format!("Unknown action: {}", x)),
+        };
+
+        let number = | s : &str | {
+            s.parse::<u64>().map_err( |_| { format!("Bad number: {}", s) } )
+        };
+        let id = | s : &str | {
+            s.parse::<u32>().map_err( |_| { format!("Bad id: {}", s) } )
+        };
+
+        Ok(JournalRecord {
+            action,
+            dev    : number(fields[1])?,
+            inode  : number(fields[2])?,
+            mode   : u32::from_str_radix(fields[3], 8).map_err(
+                |_| { format!("Bad mode: {}", fields[3]) }
+            )?,
+            uid    : id(fields[4])?,
+            gid    : id(fields[5])?,
+            atime  : parse_time(fields[6])?,
+            mtime  : parse_time(fields[7])?,
+            path   : unescape_field(fields[8])?,
+            leader : unescape_field(fields[9])?,
+        })
+    }
+}
+
+impl Journal {
+
+    pub fn open(path : &str) -> Result<Self> {
+        let open = || -> io::Result<File> {
+            let mut file = OpenOptions::new()
+                .create(true).append(true).open(path)?;
+
+            if file.metadata()?.len() == 0 {
+                writeln!(file, "{}", JOURNAL_HEADER)?;
+            }
+
+            Ok(file)
+        };
+
+        let file = open().map_err( |e| { Error::output(path, e) } )?;
+
+        Ok(Journal { path : path.to_string(), file : RefCell::new(file) })
+    }
+
+    // Records are written unbuffered and synced, so that each one is on disk
+    // before the file it describes is removed
+    pub fn record(&self, record : &JournalRecord) -> Result<()> {
+        let mut file = self.file.borrow_mut();
+
+        writeln!(file, "{}", record.to_line())
+            .and_then( |_| { file.sync_data() } )
+            .map_err(  |e| { Error::output(&self.path, e) } )
+    }
+
+    pub fn sync(&self) -> Result<()> {
+        self.file.borrow().sync_all()
+            .map_err( |e| { Error::output(&self.path, e) } )
+    }
+}
+
+pub fn read_journal(path : &str) -> Result<Vec<JournalRecord>> {
+    let text = fs::read_to_string(path).map_err(
+        |e| { Error::journal(path, e) }
+    )?;
+
+    let mut result = Vec::new();
+
+    for (idx, line) in text.lines().enumerate() {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let record = JournalRecord::from_line(line).map_err(
+            |message| {
+                Error::Journal {
+                    path : path.to_string(), line : idx + 1, message
+                }
+            }
+        )?;
+
+        result.push(record);
+    }
+
+    Ok(result)
+}
+
+fn is_still_linked(record : &JournalRecord) -> io::Result<bool> {
+    let meta = fs::symlink_metadata(&record.path)?;
+
+    match record.action {
+        DedupOp::Symlink  => {
+            if ! meta.file_type().is_symlink() {
+                return Ok(false);
+            }
+
+            // A symlink replaced by another one is left alone
+            let target = calculate_relative_path(&record.leader, &record.path);
+            Ok(fs::read_link(&record.path)?.as_os_str() == target.as_str())
+        },
+        DedupOp::Hardlink => {
+            let leader = fs::symlink_metadata(&record.leader)?;
+            Ok((meta.dev() == leader.dev()) && (meta.ino() == leader.ino()))
+        },
+        DedupOp::Metadata => {
+            Ok((meta.dev() == record.dev) && (meta.ino() == record.inode))
+        },
+        DedupOp::Remove   => Ok(false),
+    }
+}
+
+// Existing files are never overwritten, numbered names are tried instead
+fn create_temp<T, F>(path : &str, create : F) -> io::Result<(String, T)>
+    where F : Fn(&str) -> io::Result<T>
+{
+    for idx in 0..MAX_TEMP_NAMES {
+        let tmp = match idx {
+            0 => format!("{}{}", path, UNDO_SUFFIX),
+            _ => format!("{}{}.{}", path, UNDO_SUFFIX, idx),
+        };
+
+        match create(&tmp) {
+            Ok(x) => return Ok((tmp, x)),
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {},
+            Err(e) => return Err(e),
+        }
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        format!("No free temporary name for '{}'", path)
+    ))
+}
+
+fn copy_with_metadata(record : &JournalRecord, dst : &mut File)
+    -> io::Result<()>
+{
+    // Copying through the link itself follows symlinks to the leader
+    io::copy(&mut File::open(&record.path)?, dst)?;
+
+    std::os::unix::fs::fchown(&*dst, Some(record.uid), Some(record.gid))?;
+    dst.set_permissions(fs::Permissions::from_mode(record.mode & 0o7777))?;
+
+    dst.set_times(
+        FileTimes::new()
+            .set_accessed(to_system_time(record.atime))
+            .set_modified(to_system_time(record.mtime))
+    )
+}
+
+// Replaces the link by an independent copy with the original metadata, or
+// by a hardlink to `link_to`, a copy already restored for the same inode.
+// Dev and inode of the original file cannot be restored.
+fn restore_record(record : &JournalRecord, link_to : Option<&str>)
+    -> io::Result<()>
+{
+    let (tmp, result) = match link_to {
+        Some(target) => {
+            let (tmp, _) = create_temp(
+                &record.path, |x| { fs::hard_link(target, x) }
+            )?;
+
+            (tmp, Ok(()))
+        },
+        None => {
+            let (tmp, mut file) = create_temp(
+                &record.path,
+                |x| { OpenOptions::new().write(true).create_new(true).open(x) }
+            )?;
+
+            let result = copy_with_metadata(record, &mut file);
+            (tmp, result)
+        },
+    };
+
+    let result = result.and_then( |_| { fs::rename(&tmp, &record.path) } );
+
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+
+    result
+}
+
+// Owner and atime are never changed by `reconcile_metadata`
+fn restore_metadata(record : &JournalRecord) -> io::Result<()> {
+    fs::set_permissions(
+        &record.path, fs::Permissions::from_mode(record.mode & 0o7777)
+    )?;
+
+    set_mtime(&record.path, record.mtime)
+}
+
+// Paths that shared an inode before deduplication are linked to the first
+// of them restored, keyed by the original dev and inode
+fn undo_record(
+    record   : &JournalRecord,
+    state    : &UndoState,
+    restored : &mut HashMap<(Dev, INode), String>,
+) -> Result<bool>
+{
+    let linked = sloppy_unwrap_or!(
+        is_still_linked(record).map_err(
+            |e| { Error::dedup("stat", &record.path, e) }
+        ),
+        state, return Ok(false)
+    );
+
+    if ! linked && (record.action == DedupOp::Metadata) {
+        warn!("Skipping '{}': it was replaced", record.path);
+        return Ok(false);
+    }
+
+    if ! linked {
+        warn!(
+            "Skipping '{}': it is no longer linked to '{}'",
+            record.path, record.leader
+        );
+        return Ok(false);
+    }
+
+    if record.action == DedupOp::Metadata {
+        if state.dry_run {
+            state.plan(&format!("  restore metadata of '{}'", record.path));
+            return Ok(true);
+        }
+
+        debug!("Restoring metadata of '{}'", record.path);
+
+        verbose_question_mark!(
+            restore_metadata(record).map_err(
+                |e| { Error::dedup("restore metadata of", &record.path, e) }
+            ),
+            state
+        );
+
+        return Ok(true);
+    }
+
+    let key     = (record.dev, record.inode);
+    let link_to = restored.get(&key).map( |x| { x.as_str() } );
+
+    if state.dry_run {
+        match link_to {
+            Some(x) => state.plan(
+                &format!("  restore '{}' as link to '{}'", record.path, x)
+            ),
+            None    => state.plan(&format!("  restore '{}'", record.path)),
+        }
+    }
+    else {
+        debug!("Restoring '{}'", record.path);
+
+        verbose_question_mark!(
+            restore_record(record, link_to).map_err(
+                |e| { Error::dedup("restore", &record.path, e) }
+            ),
+            state
+        );
+    }
+
+    restored.entry(key).or_insert_with( || { record.path.clone() } );
+
+    Ok(true)
+}
+
+// Records are undone in reverse order, so that files deduplicated several
+// times are restored to their earliest state. Dry run plan is written to
+// `plan`.
+pub fn undo_journal(
+    path           : &str,
+    abort_on_error : bool,
+    dry_run        : bool,
+    progress       : &dyn ProgressSink,
+    plan           : &mut dyn Write,
+) -> Result<UndoSummary>
+{
+    let records = read_journal(path)?;
+    let state   = UndoState {
+        abort_on_error, dry_run, progress, plan : RefCell::new(plan)
+    };
+
+    let title   = "Restoring";
+
+    let mut restored : HashMap<(Dev, INode), String> = HashMap::new();
+
+    let mut summary = UndoSummary {
+        n_records : records.len(), ..Default::default()
+    };
+
+    if dry_run {
+        state.plan("Dry run:");
+    }
+
+    progress.event(&ProgressEvent::StageStart {
+        name : title, total : records.len() as u64
+    });
+
+    for record in records.iter().rev() {
+        // Errors are already reported by `undo_record`
+        match undo_record(record, &state, &mut restored) {
+            Ok(true)  => { summary.n_restored += 1; },
+            Ok(false) => { summary.n_skipped  += 1; },
+            Err(e)    => {
+                if abort_on_error {
+                    return Err(e);
+                }
+
+                summary.n_skipped += 1;
+            },
+        }
+
+        progress.event(&ProgressEvent::StageTick {
+            name : title, path : Some(&record.path)
+        });
+    }
+
+    progress.event(&ProgressEvent::StageFinish { name : title });
+
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_record(path : &str) -> JournalRecord {
+        JournalRecord {
+            action : DedupOp::Hardlink,
+            path   : path.to_string(),
+            leader : "/leader".to_string(),
+            dev    : 1,
+            inode  : 2,
+            mode   : 0o100644,
+            uid    : 1000,
+            gid    : 100,
+            atime  : (1600000000, 5),
+            mtime  : (-10, 999999999),
+        }
+    }
+
+    #[test]
+    fn test_journal_record_line() {
+        let record = make_record("/a\tb\\c\nd");
+        let line   = record.to_line();
+
+        assert!(! line.contains('\n'));
+        assert_eq!(JournalRecord::from_line(&line), Ok(record));
+    }
+
+    #[test]
+    fn test_journal_record_metadata_line() {
+        let record = JournalRecord {
+            action : DedupOp::Metadata, leader : "/a".to_string(),
+            ..make_record("/a")
+        };
+
+        assert!(record.to_line().starts_with("metadata\t"));
+        assert_eq!(JournalRecord::from_line(&record.to_line()), Ok(record));
+    }
+
+    #[test]
+    fn test_journal_record_bad_line() {
+        assert!(JournalRecord::from_line("hardlink\t1\t2").is_err());
+        assert!(
+            JournalRecord::from_line(
+                &make_record("/a").to_line().replace("hardlink", "copy")
+            ).is_err()
+        );
+    }
+
+    #[test]
+    fn test_to_system_time() {
+        assert_eq!(
+            to_system_time((1, 5)), UNIX_EPOCH + Duration::new(1, 5)
+        );
+        assert_eq!(
+            to_system_time((-2, 500)),
+            UNIX_EPOCH - Duration::new(1, 999999500)
+        );
+    }
+}
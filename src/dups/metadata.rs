@@ -0,0 +1,221 @@
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::unix::prelude::*;
+use std::path::Path;
+
+use error::{Error, Result};
+use fs_entry::FSEntry;
+use dups::dedup::find_group_leader_index;
+use dups::journal::{Journal, JournalRecord};
+use utils::progress::{DedupOp, ProgressSink};
+use utils::xattr::{XAttrs, read_xattrs};
+
+// What to do with files whose metadata would be lost by replacing them with
+// a link to the group leader
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub enum MetadataPolicy {
+    Ignore, Refuse, Split, Reconcile,
+}
+
+#[derive(Clone)]
+#[derive(Debug)]
+#[derive(PartialOrd)]
+#[derive(Ord)]
+#[derive(PartialEq)]
+#[derive(Eq)]
+struct MetaKey {
+    uid    : u32,
+    gid    : u32,
+    mode   : u32,
+    xattrs : XAttrs,
+}
+
+struct ReconcileState<'a> {
+    abort_on_error : bool,
+    progress       : &'a dyn ProgressSink,
+}
+
+// Entries with unreadable xattrs get no key and are never linked
+fn meta_key(entry : &FSEntry) -> Option<MetaKey> {
+    let path = &entry.paths[0].path;
+
+    match read_xattrs(Path::new(path)) {
+        Ok(xattrs) => Some(MetaKey {
+            uid  : entry.meta.uid,
+            gid  : entry.meta.gid,
+            mode : entry.meta.mode & 0o7777,
+            xattrs,
+        }),
+        Err(e) => {
+            warn!("Not deduplicating '{}': cannot read xattrs: {}", path, e);
+            None
+        },
+    }
+}
+
+fn refuse_incompatible(group : Vec<FSEntry>) -> Vec<FSEntry> {
+    let keys : Vec<Option<MetaKey>> = group.iter().map(meta_key).collect();
+    let leader_index = find_group_leader_index(&group);
+
+    if keys[leader_index].is_none() {
+        return Vec::new();
+    }
+
+    let leader_path = group[leader_index].paths[0].path.clone();
+
+    group.into_iter().zip(keys.iter())
+        .filter(
+            |(entry, key)| {
+                let result = **key == keys[leader_index];
+
+                if ! result {
+                    info!(
+                        "Not deduplicating '{}': owner, mode or xattrs \
+                        differ from '{}'", entry.paths[0].path, leader_path
+                    );
+                }
+
+                result
+            }
+        )
+        .map( |(entry, _)| { entry } )
+        .collect()
+}
+
+fn split_incompatible(group : Vec<FSEntry>) -> Vec<Vec<FSEntry>> {
+    let mut result : BTreeMap<MetaKey, Vec<FSEntry>> = BTreeMap::new();
+
+    for entry in group.into_iter() {
+        if let Some(key) = meta_key(&entry) {
+            result.entry(key).or_default().push(entry);
+        }
+    }
+
+    result.into_values().collect()
+}
+
+pub fn apply_metadata_policy(
+    duplicate_groups : Vec<Vec<FSEntry>>, policy : MetadataPolicy
+) -> Vec<Vec<FSEntry>>
+{
+    let groups : Vec<Vec<FSEntry>> = match policy {
+        MetadataPolicy::Refuse => duplicate_groups.into_iter()
+            .map(refuse_incompatible)
+            .collect(),
+        MetadataPolicy::Split  => duplicate_groups.into_iter()
+            .flat_map(split_incompatible)
+            .collect(),
+        _ => return duplicate_groups,
+    };
+
+    groups.into_iter().filter( |x| { x.len() > 1 } ).collect()
+}
+
+pub fn set_mtime(path : &str, mtime : (i64, i64)) -> io::Result<()> {
+    let c_path = CString::new(path.as_bytes()).map_err(
+        |e| { io::Error::new(io::ErrorKind::InvalidInput, e) }
+    )?;
+
+    let times = [
+        libc::timespec { tv_sec : 0, tv_nsec : libc::UTIME_OMIT },
+        libc::timespec {
+            tv_sec  : mtime.0 as libc::time_t,
+            tv_nsec : mtime.1 as libc::c_long,
+        },
+    ];
+
+    let result = unsafe {
+        libc::utimensat(libc::AT_FDCWD, c_path.as_ptr(), times.as_ptr(), 0)
+    };
+
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+// The leader keeps its owner and gets the newest mtime and the union of the
+// read permissions of the group
+fn reconciled_metadata(group : &[FSEntry], leader : &FSEntry)
+    -> (u32, (i64, i64))
+{
+    let mode = group.iter().fold(
+        leader.meta.mode & 0o7777, |acc, x| { acc | (x.meta.mode & 0o444) }
+    );
+    let mtime = group.iter().map( |x| { x.meta.mtime } ).max().unwrap();
+
+    (mode, mtime)
+}
+
+fn reconcile_leader(leader : &FSEntry, mode : u32, mtime : (i64, i64))
+    -> io::Result<()>
+{
+    let path = &leader.leader_path().path;
+
+    if mode != (leader.meta.mode & 0o7777) {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    }
+
+    if mtime != leader.meta.mtime {
+        set_mtime(path, mtime)?;
+    }
+
+    Ok(())
+}
+
+// Protected leaders are never modified. The old mode and mtime of a leader
+// are journaled before it is changed, like replaced files in `deduplicate`.
+pub fn reconcile_metadata(
+    duplicate_groups : &[&[FSEntry]],
+    abort_on_error   : bool,
+    progress         : &dyn ProgressSink,
+    journal          : Option<&Journal>,
+) -> Result<()>
+{
+    let state = ReconcileState { abort_on_error, progress };
+
+    for group in duplicate_groups.iter() {
+        let leader = &group[find_group_leader_index(group)];
+        let path   = &leader.leader_path().path;
+
+        if leader.is_protected() {
+            debug!("Not reconciling metadata of protected file '{}'", path);
+            continue;
+        }
+
+        let (mode, mtime) = reconciled_metadata(group, leader);
+
+        if (mode == (leader.meta.mode & 0o7777)) && (mtime == leader.meta.mtime)
+        {
+            continue;
+        }
+
+        if let Some(journal) = journal {
+            let meta = sloppy_unwrap_or_continue!(
+                fs::symlink_metadata(path).map_err(
+                    |e| { Error::dedup("stat", path, e) }
+                ),
+                state
+            );
+
+            journal.record(
+                &JournalRecord::new(DedupOp::Metadata, path, path, &meta)
+            )?;
+        }
+
+        sloppy_unwrap_or_continue!(
+            reconcile_leader(leader, mode, mtime).map_err(
+                |e| { Error::dedup("reconcile metadata of", path, e) }
+            ),
+            state
+        );
+    }
+
+    Ok(())
+}
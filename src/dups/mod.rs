@@ -1,4 +1,10 @@
+pub mod hasher;
 pub mod heuristics;
 pub mod eval;
+pub mod compare;
 pub mod dedup;
+pub mod filter;
+pub mod journal;
+pub mod metadata;
+pub mod script;
 pub mod search;
@@ -0,0 +1,141 @@
+use std::io::{self, Write};
+
+use args::DedupAction;
+use fs_entry::{FSEntry, FSPath};
+use dups::dedup::find_group_leader_index;
+use utils::path::calculate_relative_path;
+
+// Guards compare inode and size of both files with the scanned values and
+// the replacement goes through a temporary name, so that a failed step
+// never leaves the original file removed. Existing files with that name are
+// left alone.
+const SCRIPT_HEADER : &str = r#"#!/bin/sh
+# Generated by fdedup. Review before running.
+set -u
+
+replaced=0
+skipped=0
+failed=0
+
+inode_of() {
+    ls -di -- "$1" | awk 'NR == 1 { print $1 }'
+}
+
+unchanged() {
+    [ -f "$1" ] && [ ! -L "$1" ] \
+        && [ "$(inode_of "$1")" = "$2" ] \
+        && [ "$(wc -c < "$1" | tr -d ' ')" = "$3" ]
+}
+
+# replace ACTION LEADER LEADER_INODE PATH PATH_INODE SIZE TARGET
+replace() {
+    if ! unchanged "$2" "$3" "$6" || ! unchanged "$4" "$5" "$6"; then
+        echo "fdedup: skipping changed file: $4" >&2
+        skipped=$((skipped + 1))
+        return
+    fi
+
+    tmp="$4.fdedup-tmp"
+
+    if [ -e "$tmp" ] || [ -L "$tmp" ]; then
+        echo "fdedup: skipping, temporary name exists: $tmp" >&2
+        skipped=$((skipped + 1))
+        return
+    fi
+
+    # ln never overwrites, so only a temporary file it created is removed
+    if ! case "$1" in
+        hardlink) ln -- "$2" "$tmp" ;;
+        symlink)  ln -s -- "$7" "$tmp" ;;
+    esac
+    then
+        echo "fdedup: failed to replace: $4" >&2
+        failed=$((failed + 1))
+        return
+    fi
+
+    if mv -f -- "$tmp" "$4"; then
+        replaced=$((replaced + 1))
+    else
+        echo "fdedup: failed to replace: $4" >&2
+        rm -f -- "$tmp"
+        failed=$((failed + 1))
+    fi
+}
+"#;
+
+const SCRIPT_FOOTER : &str = r#"
+echo "fdedup: replaced $replaced, skipped $skipped, failed $failed" >&2
+[ "$skipped" -eq 0 ] && [ "$failed" -eq 0 ]
+"#;
+
+// Single quotes preserve everything literally, including newlines, except
+// the single quote itself
+pub fn shell_quote(s : &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+fn write_group<W : Write>(
+    out : &mut W, group : &[FSEntry], action : DedupAction
+) -> io::Result<()>
+{
+    let leader_index = find_group_leader_index(group);
+    let leader       = &group[leader_index];
+    let leader_path  = &leader.leader_path().path;
+
+    writeln!(out, "\n# Size: {}", leader.size)?;
+
+    for (idx, entry) in group.iter().enumerate() {
+
+        if idx == leader_index {
+            continue;
+        }
+
+        for FSPath { path, protected, .. } in entry.paths.iter() {
+            if *protected {
+                continue;
+            }
+
+            let (name, target) = match action {
+                DedupAction::Symlink => (
+                    "symlink", calculate_relative_path(leader_path, path)
+                ),
+                _ => ("hardlink", leader_path.clone()),
+            };
+
+            writeln!(
+                out, "replace {} {} {} {} {} {} {}",
+                name, shell_quote(leader_path), leader.inode,
+                shell_quote(path), entry.inode, entry.size,
+                shell_quote(&target)
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+pub fn write_script<W : Write>(
+    out : &mut W, duplicate_groups : &[Vec<FSEntry>], action : DedupAction
+) -> io::Result<()>
+{
+    write!(out, "{}", SCRIPT_HEADER)?;
+
+    for group in duplicate_groups.iter() {
+        write_group(out, group, action)?;
+    }
+
+    write!(out, "{}", SCRIPT_FOOTER)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(shell_quote("a b"), "'a b'");
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+        assert_eq!(shell_quote("$x\n`y`"), "'$x\n`y`'");
+    }
+}
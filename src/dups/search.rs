@@ -1,9 +1,13 @@
 use std::cmp::Ordering;
-use std::io;
 
-use fs_entry::FSEntry;
-use dups::heuristics::HeuristicFn;
+use error::Result;
+use fs_entry::{FSEntry, HKey};
+use dups::compare::compare_files;
+use dups::heuristics::Heuristic;
 use dups::eval::Evaluator;
+use utils::interrupt::Interrupt;
+use utils::reader::Reader;
+use utils::progress::{ProgressEvent, ProgressSink};
 
 pub fn compare_entries(a : &FSEntry, b : &FSEntry, cmp_dev : bool) -> Ordering
 {
@@ -15,11 +19,27 @@ pub fn compare_entries(a : &FSEntry, b : &FSEntry, cmp_dev : bool) -> Ordering
     }
 
     match a.size.cmp(&b.size) {
+        Ordering::Equal => {},
+        other           => return other,
+    }
+
+    match a.attrs.cmp(&b.attrs) {
         Ordering::Equal => a.hvalue.cmp(&b.hvalue),
         other           => other,
     }
 }
 
+// Entries with equal keys are ordered by dev and inode, so that the order
+// within groups does not depend on the order in which they were read
+fn sort_entries(entries : &mut [FSEntry], cmp_dev : bool) {
+    entries.sort_by(
+        | a, b | {
+            compare_entries(a, b, cmp_dev)
+                .then_with( || { (a.dev, a.inode).cmp(&(b.dev, b.inode)) } )
+        }
+    );
+}
+
 fn add_entry_to_list_if_nontrivial(
     entries : &mut Vec<FSEntry>, prev_entry : FSEntry, cmp_dev : bool
 )
@@ -40,7 +60,7 @@ pub fn remove_unique_entries_by_heuristic(
         return entries;
     }
 
-    entries.sort_by( | a, b | compare_entries(a, b, cmp_dev) );
+    sort_entries(&mut entries, cmp_dev);
 
     let mut result : Vec<FSEntry> = Vec::new();
     let mut prev_entry : FSEntry = entries[0].clone();
@@ -61,24 +81,201 @@ pub fn remove_unique_entries_by_heuristic(
     result
 }
 
-pub fn remove_unique_entries_by_heuristic_fn(
+// Keys that do not depend on contents are moved to `attrs`, where later
+// stages do not replace them. Entries without such a key are dropped.
+fn keep_attribute_keys(entries : &mut Vec<FSEntry>) {
+    entries.retain( |x| { x.hvalue.is_some() } );
+
+    for entry in entries.iter_mut() {
+        entry.attrs.push(entry.hvalue.take().unwrap());
+    }
+}
+
+pub fn remove_unique_entries_by_stage(
     mut entries     : Vec<FSEntry>,
     cmp_dev         : bool,
-    title           : &str,
-    verbose         : bool,
+    heuristic       : &dyn Heuristic,
+    reader          : &Reader,
+    progress        : &dyn ProgressSink,
+    interrupt       : &Interrupt,
     abort_on_error  : bool,
-    func            : Box<HeuristicFn>
-) -> io::Result<Vec<FSEntry>>
+) -> Result<Vec<FSEntry>>
 {
+    let keep = ! heuristic.depends_on_content();
+
+    if keep {
+        for entry in entries.iter_mut() {
+            entry.hvalue = None;
+        }
+    }
+
+    let title    = format!("Grouping by {}", heuristic.name());
     let mut eval = Evaluator::new(
-        entries.len(), title, verbose, abort_on_error, func
+        entries.len(), &title, progress, interrupt, abort_on_error, heuristic,
+        reader
     );
 
     eval.evaluate(&mut entries)?;
 
+    if interrupt.is_set() {
+        return Ok(entries);
+    }
+
+    if keep {
+        keep_attribute_keys(&mut entries);
+    }
+
     Ok(remove_unique_entries_by_heuristic(entries, cmp_dev))
 }
 
+// Keys of compared files extend the previous key by the class index, so
+// that classes of different groups never share a key
+fn compared_key(entry : &FSEntry, class : usize) -> HKey {
+    let mut result : Vec<u8> = vec![ 0 ];
+
+    if let Some(key) = &entry.hvalue {
+        result.extend_from_slice(&key.0);
+    }
+
+    result.extend_from_slice(&(class as u64).to_be_bytes());
+    HKey(result)
+}
+
+fn hashed_key(key : HKey) -> HKey {
+    let mut result : Vec<u8> = vec![ 1 ];
+    result.extend_from_slice(&key.0);
+    HKey(result)
+}
+
+// Groups that are not compared, because of an error or an interruption, are
+// moved to `rest`
+fn compare_groups(
+    groups    : Vec<Vec<FSEntry>>,
+    rest      : &mut Vec<FSEntry>,
+    reader    : &Reader,
+    progress  : &dyn ProgressSink,
+    interrupt : &Interrupt,
+) -> Vec<(Vec<FSEntry>, Vec<usize>)>
+{
+    let mut result : Vec<(Vec<FSEntry>, Vec<usize>)> = Vec::new();
+
+    if groups.is_empty() {
+        return result;
+    }
+
+    let title = "Comparing files";
+    let total : usize = groups.iter().map( |x| { x.len() } ).sum();
+
+    progress.event(&ProgressEvent::StageStart {
+        name : title, total : total as u64
+    });
+
+    for group in groups.into_iter() {
+        if interrupt.is_set() {
+            rest.extend(group);
+            continue;
+        }
+
+        let paths : Vec<&str> = group.iter()
+            .map( |x| { x.paths[0].path.as_str() } )
+            .collect();
+
+        // Errors are reported when the group is evaluated instead
+        let classes = match compare_files(&paths, reader) {
+            Ok(classes) => classes,
+            Err(e)      => {
+                debug!("Comparison failed, hashing instead: {}", e);
+                rest.extend(group);
+                continue;
+            },
+        };
+
+        for path in paths.iter() {
+            progress.event(&ProgressEvent::StageTick {
+                name : title, path : Some(path)
+            });
+        }
+
+        result.push((group, classes));
+    }
+
+    progress.event(&ProgressEvent::StageFinish { name : title });
+
+    result
+}
+
+// Groups of up to `max_compare` entries are compared byte by byte, which
+// stops at the first difference, the rest is evaluated by the exact
+// `heuristic`. Both kinds of keys are tagged, so that the result groups
+// like the heuristic alone would.
+#[allow(clippy::too_many_arguments)]
+pub fn remove_unique_entries_by_comparison(
+    entries         : Vec<FSEntry>,
+    cmp_dev         : bool,
+    heuristic       : &dyn Heuristic,
+    reader          : &Reader,
+    max_compare     : usize,
+    progress        : &dyn ProgressSink,
+    interrupt       : &Interrupt,
+    abort_on_error  : bool,
+) -> Result<Vec<FSEntry>>
+{
+    let mut small : Vec<Vec<FSEntry>> = Vec::new();
+    let mut large : Vec<FSEntry>      = Vec::new();
+
+    for group in group_by_heuristic(entries, cmp_dev).into_iter() {
+        if group.len() < 2 {
+            continue;
+        }
+
+        // Small files are evaluated from their digest without another read
+        let small_file = reader.is_small(group[0].size)
+            && heuristic.reads_raw_contents();
+
+        if (group.len() <= max_compare) && ! small_file {
+            small.push(group);
+        }
+        else {
+            large.extend(group);
+        }
+    }
+
+    // Keys are assigned only once all groups are compared, see `Evaluator`
+    let classes = compare_groups(
+        small, &mut large, reader, progress, interrupt
+    );
+
+    if ! large.is_empty() && ! interrupt.is_set() {
+        large = remove_unique_entries_by_stage(
+            large, cmp_dev, heuristic, reader, progress, interrupt,
+            abort_on_error
+        )?;
+    }
+
+    let mut result : Vec<FSEntry> = Vec::new();
+
+    if interrupt.is_set() {
+        result.extend(classes.into_iter().flat_map( |(group, _)| group ));
+        result.extend(large);
+
+        return Ok(result);
+    }
+
+    for (group, group_classes) in classes.into_iter() {
+        for (mut entry, class) in group.into_iter().zip(group_classes) {
+            entry.hvalue = Some(compared_key(&entry, class));
+            result.push(entry);
+        }
+    }
+
+    for mut entry in large.into_iter() {
+        entry.hvalue = entry.hvalue.take().map(hashed_key);
+        result.push(entry);
+    }
+
+    Ok(remove_unique_entries_by_heuristic(result, cmp_dev))
+}
+
 pub fn count_duplicate_entries_files(
     entries : &[FSEntry], cmp_dev : bool
 ) -> (usize, usize)
@@ -113,7 +310,7 @@ pub fn group_by_heuristic(
     }
 
     // TODO: replace by is_sorted once it is stabilized
-    entries.sort_by( | a, b | compare_entries(a, b, cmp_dev) );
+    sort_entries(&mut entries, cmp_dev);
 
     let mut result : Vec<Vec<FSEntry>> = Vec::new();
     let mut group  : Vec<FSEntry>      = vec![ entries[0].clone() ];
@@ -140,13 +337,19 @@ pub fn group_by_heuristic(
 #[cfg(test)]
 mod tests {
     use super::*;
-    use fs_entry::{Heuristic, INode};
+    use fs_entry::{FSPath, HKey, INode};
+
+    fn key(value : u64) -> Option<HKey> {
+        Some(HKey::from(value))
+    }
 
     fn init_test_entry(
-        inode : INode, size : u64, hvalue : Heuristic
+        inode : INode, size : u64, hvalue : Option<HKey>
     ) -> FSEntry
     {
-        let mut result = FSEntry::new(0, inode, size, 0, "path".to_string());
+        let mut result = FSEntry::new(
+            0, inode, size, 0, FSPath::new("path".to_string(), 0, false)
+        );
         result.hvalue  = hvalue;
 
         result
@@ -155,7 +358,7 @@ mod tests {
     #[test]
     pub fn test_remove_unique_entries_single_collapse()
     {
-        let entry = init_test_entry(0, 0, Heuristic::Null);
+        let entry = init_test_entry(0, 0, None);
 
         let test_entries = remove_unique_entries_by_heuristic(
             vec![ entry ], false
@@ -169,9 +372,9 @@ mod tests {
     pub fn test_remove_unique_entries_no_unique()
     {
         let entries = vec![
-            init_test_entry(0, 0, Heuristic::Null),
-            init_test_entry(1, 0, Heuristic::Null),
-            init_test_entry(2, 0, Heuristic::Null),
+            init_test_entry(0, 0, None),
+            init_test_entry(1, 0, None),
+            init_test_entry(2, 0, None),
         ];
 
         let null_entries = entries.clone();
@@ -184,18 +387,18 @@ mod tests {
     pub fn test_remove_unique_entries_by_size()
     {
         let entries = vec![
-            init_test_entry(0, 0, Heuristic::Null),
-            init_test_entry(1, 2, Heuristic::Null),
-            init_test_entry(2, 1, Heuristic::Null),
-            init_test_entry(3, 2, Heuristic::Null),
-            init_test_entry(4, 0, Heuristic::Null),
+            init_test_entry(0, 0, None),
+            init_test_entry(1, 2, None),
+            init_test_entry(2, 1, None),
+            init_test_entry(3, 2, None),
+            init_test_entry(4, 0, None),
         ];
 
         let null_entries = vec![
-            init_test_entry(0, 0, Heuristic::Null),
-            init_test_entry(4, 0, Heuristic::Null),
-            init_test_entry(1, 2, Heuristic::Null),
-            init_test_entry(3, 2, Heuristic::Null),
+            init_test_entry(0, 0, None),
+            init_test_entry(4, 0, None),
+            init_test_entry(1, 2, None),
+            init_test_entry(3, 2, None),
         ];
 
         let test_entries = remove_unique_entries_by_heuristic(entries, false);
@@ -207,17 +410,17 @@ mod tests {
     pub fn test_remove_unique_entries_by_hvalue()
     {
         let entries = vec![
-            init_test_entry(0, 0, Heuristic::Size(1)),
-            init_test_entry(1, 0, Heuristic::Size(0)),
-            init_test_entry(2, 0, Heuristic::Size(2)),
-            init_test_entry(3, 0, Heuristic::Size(1)),
-            init_test_entry(4, 0, Heuristic::Size(1)),
+            init_test_entry(0, 0, key(1)),
+            init_test_entry(1, 0, key(0)),
+            init_test_entry(2, 0, key(2)),
+            init_test_entry(3, 0, key(1)),
+            init_test_entry(4, 0, key(1)),
         ];
 
         let null_entries = vec![
-            init_test_entry(0, 0, Heuristic::Size(1)),
-            init_test_entry(3, 0, Heuristic::Size(1)),
-            init_test_entry(4, 0, Heuristic::Size(1)),
+            init_test_entry(0, 0, key(1)),
+            init_test_entry(3, 0, key(1)),
+            init_test_entry(4, 0, key(1)),
         ];
 
         let test_entries = remove_unique_entries_by_heuristic(entries, false);
@@ -229,28 +432,28 @@ mod tests {
     pub fn test_group_entries_by_size()
     {
         let entries = vec![
-            init_test_entry(0, 0, Heuristic::Null),
-            init_test_entry(1, 2, Heuristic::Null),
-            init_test_entry(2, 1, Heuristic::Null),
-            init_test_entry(3, 2, Heuristic::Null),
-            init_test_entry(4, 0, Heuristic::Null),
-            init_test_entry(5, 5, Heuristic::Null),
+            init_test_entry(0, 0, None),
+            init_test_entry(1, 2, None),
+            init_test_entry(2, 1, None),
+            init_test_entry(3, 2, None),
+            init_test_entry(4, 0, None),
+            init_test_entry(5, 5, None),
         ];
 
         let null_entries = vec![
             vec![
-                init_test_entry(0, 0, Heuristic::Null),
-                init_test_entry(4, 0, Heuristic::Null),
+                init_test_entry(0, 0, None),
+                init_test_entry(4, 0, None),
             ],
             vec![
-                init_test_entry(2, 1, Heuristic::Null),
+                init_test_entry(2, 1, None),
             ],
             vec![
-                init_test_entry(1, 2, Heuristic::Null),
-                init_test_entry(3, 2, Heuristic::Null),
+                init_test_entry(1, 2, None),
+                init_test_entry(3, 2, None),
             ],
             vec![
-                init_test_entry(5, 5, Heuristic::Null),
+                init_test_entry(5, 5, None),
             ],
         ];
 
@@ -263,28 +466,28 @@ mod tests {
     pub fn test_group_entries_by_hvalue()
     {
         let entries = vec![
-            init_test_entry(0, 0, Heuristic::Size(0)),
-            init_test_entry(1, 0, Heuristic::Size(2)),
-            init_test_entry(2, 0, Heuristic::Size(1)),
-            init_test_entry(3, 0, Heuristic::Size(2)),
-            init_test_entry(4, 0, Heuristic::Size(0)),
-            init_test_entry(5, 0, Heuristic::Size(5)),
+            init_test_entry(0, 0, key(0)),
+            init_test_entry(1, 0, key(2)),
+            init_test_entry(2, 0, key(1)),
+            init_test_entry(3, 0, key(2)),
+            init_test_entry(4, 0, key(0)),
+            init_test_entry(5, 0, key(5)),
         ];
 
         let null_entries = vec![
             vec![
-                init_test_entry(0, 0, Heuristic::Size(0)),
-                init_test_entry(4, 0, Heuristic::Size(0)),
+                init_test_entry(0, 0, key(0)),
+                init_test_entry(4, 0, key(0)),
             ],
             vec![
-                init_test_entry(2, 0, Heuristic::Size(1)),
+                init_test_entry(2, 0, key(1)),
             ],
             vec![
-                init_test_entry(1, 0, Heuristic::Size(2)),
-                init_test_entry(3, 0, Heuristic::Size(2)),
+                init_test_entry(1, 0, key(2)),
+                init_test_entry(3, 0, key(2)),
             ],
             vec![
-                init_test_entry(5, 0, Heuristic::Size(5)),
+                init_test_entry(5, 0, key(5)),
             ],
         ];
 
@@ -293,16 +496,39 @@ mod tests {
         assert_eq!(test_entries, null_entries);
     }
 
+    #[test]
+    pub fn test_group_entries_by_attrs()
+    {
+        let mut entries = vec![
+            init_test_entry(0, 0, key(0)),
+            init_test_entry(1, 0, key(0)),
+            init_test_entry(2, 0, key(0)),
+        ];
+
+        entries[0].attrs.push(HKey::from(0o644u32));
+        entries[1].attrs.push(HKey::from(0o600u32));
+        entries[2].attrs.push(HKey::from(0o644u32));
+
+        let null_entries = vec![
+            vec![ entries[1].clone() ],
+            vec![ entries[0].clone(), entries[2].clone() ],
+        ];
+
+        let test_entries = group_by_heuristic(entries, false);
+
+        assert_eq!(test_entries, null_entries);
+    }
+
     #[test]
     pub fn test_count_duplicates()
     {
         let mut entries = vec![
-            init_test_entry(0, 0, Heuristic::Null),
-            init_test_entry(1, 2, Heuristic::Null),
-            init_test_entry(2, 1, Heuristic::Null),
-            init_test_entry(3, 2, Heuristic::Null),
-            init_test_entry(4, 0, Heuristic::Null),
-            init_test_entry(5, 5, Heuristic::Null),
+            init_test_entry(0, 0, None),
+            init_test_entry(1, 2, None),
+            init_test_entry(2, 1, None),
+            init_test_entry(3, 2, None),
+            init_test_entry(4, 0, None),
+            init_test_entry(5, 5, None),
         ];
 
         entries.sort_by( | a, b | compare_entries(a, b, false) );
@@ -1,22 +1,25 @@
+macro_rules! report_error {
+    ( $state:expr, $err:expr ) => {
+        $state.progress.event(
+            &$crate::utils::progress::ProgressEvent::Error { error : &$err }
+        )
+    };
+}
 
 #[macro_export]
 macro_rules! sloppy_unwrap_or {
-    ( $x:expr, $state:expr, $msg:expr, $err_handle:expr ) => {
+    ( $x:expr, $state:expr, $err_handle:expr ) => {
         match $x {
             Ok(x)  => x,
             Err(e) => {
-                if $state.abort_on_error {
-                    if $msg.len() > 0 {
-                        error!("{} : {}", $msg, e);
-                    }
+                report_error!($state, e);
 
+                if $state.abort_on_error {
+                    error!("{}", e);
                     return Err(e);
                 }
                 else {
-                    if $msg.len() > 0 {
-                        warn!("{} : {}", $msg, e);
-                    }
-
+                    warn!("{}", e);
                     $err_handle
                 }
             }
@@ -25,15 +28,17 @@ macro_rules! sloppy_unwrap_or {
 }
 
 macro_rules! verbose_question_mark {
-    ( $x:expr, $state:expr, $msg:expr ) => {
+    ( $x:expr, $state:expr ) => {
         match $x {
             Ok(x)  => x,
             Err(e) => {
+                report_error!($state, e);
+
                 if $state.abort_on_error {
-                    error!("{} : {}", $msg, e);
+                    error!("{}", e);
                 }
                 else {
-                    warn!("{} : {}", $msg, e);
+                    warn!("{}", e);
                 }
 
                 return Err(e);
@@ -43,8 +48,23 @@ macro_rules! verbose_question_mark {
 }
 
 macro_rules! sloppy_unwrap_or_continue {
-    ( $x:expr, $abort:expr, $msg:expr ) => {
-        sloppy_unwrap_or!($x, $abort, $msg, continue)
+    ( $x:expr, $state:expr ) => {
+        sloppy_unwrap_or!($x, $state, continue)
     };
 }
 
+// For errors that were already reported where they occurred
+macro_rules! quiet_unwrap_or_continue {
+    ( $x:expr, $state:expr ) => {
+        match $x {
+            Ok(x)  => x,
+            Err(e) => {
+                if $state.abort_on_error {
+                    return Err(e);
+                }
+
+                continue;
+            }
+        }
+    };
+}
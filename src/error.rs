@@ -0,0 +1,175 @@
+use std::error;
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+#[derive(Debug)]
+pub enum Error {
+    Config(String),
+    Scan      { op : &'static str, path : String, source : io::Error },
+    Filter    { pattern : String, message : String },
+    Heuristic { stage : String, path : String, source : io::Error },
+    Dedup     { op : &'static str, path : String, source : io::Error },
+    Output    { path : String, source : io::Error },
+    Journal   { path : String, line : usize, message : String },
+    JournalIo { path : String, source : io::Error },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+fn path_to_string<P : AsRef<Path>>(path : P) -> String {
+    path.as_ref().to_string_lossy().to_string()
+}
+
+impl Error {
+
+    pub fn scan<P : AsRef<Path>>(
+        op : &'static str, path : P, source : io::Error
+    ) -> Self
+    {
+        Error::Scan { op, path : path_to_string(path), source }
+    }
+
+    pub fn heuristic<P : AsRef<Path>>(
+        stage : &str, path : P, source : io::Error
+    ) -> Self
+    {
+        Error::Heuristic {
+            stage : stage.to_string(), path : path_to_string(path), source
+        }
+    }
+
+    pub fn dedup<P : AsRef<Path>>(
+        op : &'static str, path : P, source : io::Error
+    ) -> Self
+    {
+        Error::Dedup { op, path : path_to_string(path), source }
+    }
+
+    pub fn output<P : AsRef<Path>>(path : P, source : io::Error) -> Self {
+        Error::Output { path : path_to_string(path), source }
+    }
+
+    pub fn journal<P : AsRef<Path>>(path : P, source : io::Error) -> Self {
+        Error::JournalIo { path : path_to_string(path), source }
+    }
+
+    pub fn stage(&self) -> &str {
+        match self {
+            Error::Config(_)         => "config",
+            Error::Scan { .. }       => "scan",
+            Error::Filter { .. }     => "filter",
+            Error::Heuristic { .. }  => "heuristic",
+            Error::Dedup { .. }      => "dedup",
+            Error::Output { .. }     => "output",
+            Error::Journal { .. }    => "journal",
+            Error::JournalIo { .. }  => "journal",
+        }
+    }
+
+    pub fn path(&self) -> Option<&str> {
+        match self {
+            Error::Scan      { path, .. } => Some(path),
+            Error::Heuristic { path, .. } => Some(path),
+            Error::Dedup     { path, .. } => Some(path),
+            Error::Output    { path, .. } => Some(path),
+            Error::Journal   { path, .. } => Some(path),
+            Error::JournalIo { path, .. } => Some(path),
+            _                             => None,
+        }
+    }
+
+    pub fn io_error(&self) -> Option<&io::Error> {
+        match self {
+            Error::Scan      { source, .. } => Some(source),
+            Error::Heuristic { source, .. } => Some(source),
+            Error::Dedup     { source, .. } => Some(source),
+            Error::Output    { source, .. } => Some(source),
+            Error::JournalIo { source, .. } => Some(source),
+            _                               => None,
+        }
+    }
+
+    pub fn kind(&self) -> Option<io::ErrorKind> {
+        self.io_error().map( |x| { x.kind() } )
+    }
+}
+
+impl fmt::Display for Error {
+
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Config(msg) => write!(f, "{}", msg),
+            Error::Scan { op, path, source } => {
+                write!(f, "Failed to {} '{}': {}", op, path, source)
+            },
+            Error::Filter { pattern, message } => {
+                write!(f, "Invalid pattern '{}': {}", pattern, message)
+            },
+            Error::Heuristic { stage, path, source } => {
+                write!(f, "{} failed for '{}': {}", stage, path, source)
+            },
+            Error::Dedup { op, path, source } => {
+                write!(f, "Failed to {} '{}': {}", op, path, source)
+            },
+            Error::Output { path, source } => {
+                write!(f, "Failed to write '{}': {}", path, source)
+            },
+            Error::Journal { path, line, message } => {
+                write!(
+                    f, "Invalid journal '{}' line {}: {}", path, line, message
+                )
+            },
+            Error::JournalIo { path, source } => {
+                write!(f, "Failed to read journal '{}': {}", path, source)
+            },
+        }
+    }
+}
+
+impl error::Error for Error {
+
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        self.io_error().map( |x| { x as &(dyn error::Error + 'static) } )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_context() {
+        let err = Error::scan(
+            "read directory", Path::new("/a"),
+            io::Error::from(io::ErrorKind::PermissionDenied)
+        );
+
+        assert_eq!(err.stage(), "scan");
+        assert_eq!(err.path(),  Some("/a"));
+        assert_eq!(err.kind(),  Some(io::ErrorKind::PermissionDenied));
+        assert!(err.to_string().starts_with("Failed to read directory '/a'"));
+    }
+
+    #[test]
+    fn test_error_journal() {
+        let err = Error::journal(
+            "/j", io::Error::from(io::ErrorKind::NotFound)
+        );
+
+        assert_eq!(err.stage(), "journal");
+        assert_eq!(err.path(),  Some("/j"));
+        assert_eq!(err.kind(),  Some(io::ErrorKind::NotFound));
+        assert!(err.to_string().starts_with("Failed to read journal '/j'"));
+    }
+
+    #[test]
+    fn test_error_config() {
+        let err = Error::Config("Bad option".to_string());
+
+        assert_eq!(err.stage(), "config");
+        assert_eq!(err.path(),  None);
+        assert_eq!(err.kind(),  None);
+        assert_eq!(err.to_string(), "Bad option");
+    }
+}
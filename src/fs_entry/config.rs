@@ -0,0 +1,113 @@
+use std::collections::HashMap;
+
+use error::Result;
+use fs_entry::{FSEntry, Dev, INode, collect_files, prepare_roots};
+use utils::interrupt::Interrupt;
+use utils::progress::ProgressSink;
+
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct ScanConfig {
+    pub roots           : Vec<String>,
+    pub references      : Vec<String>,
+    pub includes        : Vec<String>,
+    pub excludes        : Vec<String>,
+    pub one_file_system : bool,
+    pub skip_fstypes    : Vec<String>,
+    pub only_fstypes    : Vec<String>,
+    pub min_file_size   : Option<u64>,
+    pub max_file_size   : Option<u64>,
+    pub abort_on_error  : bool,
+}
+
+impl Default for ScanConfig {
+    fn default() -> Self {
+        ScanConfig {
+            roots           : Vec::new(),
+            references      : Vec::new(),
+            includes        : Vec::new(),
+            excludes        : Vec::new(),
+            one_file_system : false,
+            skip_fstypes    : Vec::new(),
+            only_fstypes    : Vec::new(),
+            min_file_size   : None,
+            max_file_size   : None,
+            abort_on_error  : true,
+        }
+    }
+}
+
+impl ScanConfig {
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn root(mut self, path : &str) -> Self {
+        self.roots.push(path.to_string());
+        self
+    }
+
+    pub fn reference(mut self, path : &str) -> Self {
+        self.references.push(path.to_string());
+        self
+    }
+
+    pub fn include(mut self, pattern : &str) -> Self {
+        self.includes.push(pattern.to_string());
+        self
+    }
+
+    pub fn exclude(mut self, pattern : &str) -> Self {
+        self.excludes.push(pattern.to_string());
+        self
+    }
+
+    pub fn one_file_system(mut self, value : bool) -> Self {
+        self.one_file_system = value;
+        self
+    }
+
+    pub fn skip_fstype(mut self, fstype : &str) -> Self {
+        self.skip_fstypes.push(fstype.to_string());
+        self
+    }
+
+    pub fn only_fstype(mut self, fstype : &str) -> Self {
+        self.only_fstypes.push(fstype.to_string());
+        self
+    }
+
+    pub fn min_file_size(mut self, size : Option<u64>) -> Self {
+        self.min_file_size = size;
+        self
+    }
+
+    pub fn max_file_size(mut self, size : Option<u64>) -> Self {
+        self.max_file_size = size;
+        self
+    }
+
+    pub fn abort_on_error(mut self, value : bool) -> Self {
+        self.abort_on_error = value;
+        self
+    }
+
+    // Stops early if `interrupt` is raised, returning the entries found so far
+    pub fn collect(&self, progress : &dyn ProgressSink, interrupt : &Interrupt)
+        -> Result<Vec<FSEntry>>
+    {
+        let mut files_map : HashMap<(Dev, INode), FSEntry> = HashMap::new();
+
+        for root in prepare_roots(&self.roots, &self.references)?.iter() {
+            if interrupt.is_set() {
+                break;
+            }
+
+            info!("Scanning '{}' for entries...", root.path);
+            collect_files(root, &mut files_map, self, progress, interrupt)?;
+        }
+
+        Ok(files_map.into_values().collect())
+    }
+}
@@ -0,0 +1,305 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use error::{Error, Result};
+use fs_entry::Dev;
+
+const MOUNTINFO_PATH : &str = "/proc/self/mountinfo";
+
+#[derive(Clone)]
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Eq)]
+pub struct MountInfo {
+    pub dev         : Dev,
+    pub mount_point : PathBuf,
+    pub fstype      : String,
+}
+
+pub struct FSTypeFilter {
+    pub skip   : Vec<String>,
+    pub only   : Vec<String>,
+    pub mounts : Vec<MountInfo>,
+    pub cache  : HashMap<Dev, bool>,
+}
+
+// Same encoding as glibc `makedev`
+pub fn make_dev(major : u64, minor : u64) -> Dev
+{
+      (minor & 0xff)
+    | ((major & 0xfff) << 8)
+    | ((minor & !0xff) << 12)
+    | ((major & !0xfff) << 32)
+}
+
+fn unescape_mount_point(s : &str) -> String
+{
+    let bytes = s.as_bytes();
+    let mut result : Vec<u8> = Vec::with_capacity(bytes.len());
+    let mut idx = 0;
+
+    while idx < bytes.len() {
+        if (bytes[idx] == b'\\') && (idx + 3 < bytes.len()) {
+            let code = std::str::from_utf8(&bytes[idx+1..idx+4]).ok()
+                .and_then(|x| { u8::from_str_radix(x, 8).ok() });
+
+            if let Some(c) = code {
+                result.push(c);
+                idx += 4;
+                continue;
+            }
+        }
+
+        result.push(bytes[idx]);
+        idx += 1;
+    }
+
+    String::from_utf8_lossy(&result).to_string()
+}
+
+fn parse_mountinfo_line(line : &str) -> Option<MountInfo>
+{
+    let mut fields = line.split(' ');
+
+    let dev         = fields.nth(2)?;
+    let mount_point = fields.nth(1)?;
+
+    fields.find( |x| { *x == "-" } )?;
+    let fstype = fields.next()?;
+
+    let mut dev_fields = dev.split(':');
+    let major : u64 = dev_fields.next()?.parse().ok()?;
+    let minor : u64 = dev_fields.next()?.parse().ok()?;
+
+    Some(MountInfo {
+        dev         : make_dev(major, minor),
+        mount_point : PathBuf::from(unescape_mount_point(mount_point)),
+        fstype      : fstype.to_string(),
+    })
+}
+
+pub fn parse_mountinfo(text : &str) -> Vec<MountInfo>
+{
+    text.lines().filter_map(parse_mountinfo_line).collect()
+}
+
+pub fn read_mountinfo() -> Result<Vec<MountInfo>>
+{
+    let text = fs::read_to_string(MOUNTINFO_PATH).map_err(
+        |e| { Error::scan("read mount table", MOUNTINFO_PATH, e) }
+    )?;
+
+    Ok(parse_mountinfo(&text))
+}
+
+// Types are matched either exactly or by their main type, that is `fuse`
+// matches `fuse.sshfs`.
+fn fstype_matches(fstype : &str, patterns : &[String]) -> bool
+{
+    patterns.iter().any(
+        |x| {
+            (fstype == x)
+            || (fstype.starts_with(x.as_str())
+                && fstype[x.len()..].starts_with('.'))
+        }
+    )
+}
+
+fn is_longer_mount(mount : &MountInfo, other : Option<&MountInfo>) -> bool
+{
+    match other {
+        Some(x) => {
+               mount.mount_point.as_os_str().len()
+            >= x.mount_point.as_os_str().len()
+        },
+        None    => true,
+    }
+}
+
+impl FSTypeFilter {
+
+    pub fn new(skip : &[String], only : &[String]) -> Result<Option<Self>>
+    {
+        if skip.is_empty() && only.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(Self::with_mounts(skip, only, read_mountinfo()?)))
+    }
+
+    pub fn with_mounts(
+        skip : &[String], only : &[String], mounts : Vec<MountInfo>
+    ) -> Self
+    {
+        FSTypeFilter {
+            skip   : skip.to_vec(),
+            only   : only.to_vec(),
+            mounts,
+            cache  : HashMap::new(),
+        }
+    }
+
+    // Bind mounts share device ids, while btrfs subvolumes have device ids
+    // that do not appear in the mount table. Therefore, the mount is looked
+    // up by the longest mount point containing `path`, preferring mounts
+    // with a matching device id.
+    pub fn find_fstype(&self, dev : Dev, path : &Path) -> Option<&str>
+    {
+        let mut by_dev  : Option<&MountInfo> = None;
+        let mut by_path : Option<&MountInfo> = None;
+
+        for mount in self.mounts.iter() {
+            if ! path.starts_with(&mount.mount_point) {
+                continue;
+            }
+
+            if (mount.dev == dev) && is_longer_mount(mount, by_dev) {
+                by_dev = Some(mount);
+            }
+
+            if is_longer_mount(mount, by_path) {
+                by_path = Some(mount);
+            }
+        }
+
+        by_dev.or(by_path).map( |x| { x.fstype.as_str() } )
+    }
+
+    pub fn accepts_fstype(&self, fstype : &str) -> bool
+    {
+        if (! self.only.is_empty()) && (! fstype_matches(fstype, &self.only))
+        {
+            return false;
+        }
+
+        ! fstype_matches(fstype, &self.skip)
+    }
+
+    pub fn accepts(&mut self, dev : Dev, path : &Path) -> bool
+    {
+        if let Some(result) = self.cache.get(&dev) {
+            return *result;
+        }
+
+        let result = match self.find_fstype(dev, path) {
+            Some(fstype) => {
+                let result = self.accepts_fstype(fstype);
+
+                if ! result {
+                    info!(
+                        "Skipping filesystem '{}' of type '{}'",
+                        path.display(), fstype
+                    );
+                }
+
+                result
+            },
+            None => {
+                warn!(
+                    "Failed to determine filesystem type of '{}'",
+                    path.display()
+                );
+                self.only.is_empty()
+            },
+        };
+
+        self.cache.insert(dev, result);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MOUNTINFO : &str = "\
+22 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw
+23 22 0:22 / /proc rw,relatime - proc proc rw
+24 22 0:45 / /mnt/remote rw shared:5 master:3 - fuse.sshfs host: rw
+25 22 0:46 / /mnt/my\\040share rw - nfs4 srv:/share rw
+26 22 259:1 / /home rw - xfs /dev/nvme0n1p1 rw
+27 26 259:1 /user /mnt/bind rw - xfs /dev/nvme0n1p1 rw
+";
+
+    fn to_strings(values : &[&str]) -> Vec<String> {
+        values.iter().map( |x| { x.to_string() } ).collect()
+    }
+
+    #[test]
+    fn test_make_dev() {
+        assert_eq!(make_dev(254, 0), 65024);
+        assert_eq!(make_dev(0, 22), 22);
+        assert_eq!(make_dev(259, 1), 0x10301);
+        assert_eq!(make_dev(0x1000, 0x100), (1 << 44) | (1 << 20));
+    }
+
+    #[test]
+    fn test_parse_mountinfo() {
+        let mounts = parse_mountinfo(MOUNTINFO);
+
+        assert_eq!(mounts.len(), 6);
+        assert_eq!(
+            mounts[2],
+            MountInfo {
+                dev         : make_dev(0, 45),
+                mount_point : PathBuf::from("/mnt/remote"),
+                fstype      : "fuse.sshfs".to_string(),
+            }
+        );
+        assert_eq!(mounts[3].mount_point, PathBuf::from("/mnt/my share"));
+        assert_eq!(mounts[3].fstype, "nfs4");
+    }
+
+    #[test]
+    fn test_find_fstype() {
+        let filter = FSTypeFilter::with_mounts(
+            &[], &[], parse_mountinfo(MOUNTINFO)
+        );
+
+        let fstype = |dev, path| { filter.find_fstype(dev, Path::new(path)) };
+
+        assert_eq!(fstype(make_dev(8, 1),   "/usr"),         Some("ext4"));
+        assert_eq!(fstype(make_dev(0, 22),  "/proc/1"),      Some("proc"));
+        assert_eq!(fstype(make_dev(259, 1), "/mnt/bind/a"),  Some("xfs"));
+        assert_eq!(fstype(make_dev(0, 99),  "/home/subvol"), Some("xfs"));
+        assert_eq!(fstype(make_dev(0, 46),  "/mnt/my share"), Some("nfs4"));
+    }
+
+    #[test]
+    fn test_accepts_fstype_skip() {
+        let filter = FSTypeFilter::with_mounts(
+            &to_strings(&[ "proc", "fuse", "nfs" ]), &[], Vec::new()
+        );
+
+        assert!(! filter.accepts_fstype("proc"));
+        assert!(! filter.accepts_fstype("fuse.sshfs"));
+        assert!(! filter.accepts_fstype("fuse"));
+        assert!(  filter.accepts_fstype("fuseblk"));
+        assert!(  filter.accepts_fstype("nfs4"));
+        assert!(  filter.accepts_fstype("ext4"));
+    }
+
+    #[test]
+    fn test_accepts_fstype_only() {
+        let filter = FSTypeFilter::with_mounts(
+            &to_strings(&[ "ext2" ]), &to_strings(&[ "ext4", "ext2" ]),
+            Vec::new()
+        );
+
+        assert!(  filter.accepts_fstype("ext4"));
+        assert!(! filter.accepts_fstype("ext2"));
+        assert!(! filter.accepts_fstype("xfs"));
+    }
+
+    #[test]
+    fn test_accepts_cached() {
+        let mut filter = FSTypeFilter::with_mounts(
+            &to_strings(&[ "proc" ]), &[], parse_mountinfo(MOUNTINFO)
+        );
+
+        assert!(! filter.accepts(make_dev(0, 22), Path::new("/proc")));
+        assert!(  filter.accepts(make_dev(8, 1),  Path::new("/usr")));
+        assert!(! filter.accepts(make_dev(0, 22), Path::new("/usr")));
+    }
+}
@@ -1,6 +1,13 @@
 pub use self::search::collect_files;
+pub use self::roots::{ScanRoot, prepare_roots};
+pub use self::config::ScanConfig;
 use std::fmt;
+use std::fs::Metadata;
+use std::os::unix::prelude::*;
 
+pub mod config;
+pub mod fstype;
+pub mod roots;
 pub mod search;
 pub mod search_state;
 
@@ -8,17 +15,39 @@ pub type INode    = u64;
 pub type Dev      = u64;
 pub type Priority = u32;
 
+// Value of a heuristic for one entry. Integers are stored big-endian, so
+// that keys sort like the values they encode.
 #[derive(Clone)]
+#[derive(Default)]
 #[derive(PartialOrd)]
 #[derive(Ord)]
 #[derive(PartialEq)]
 #[derive(Eq)]
-pub enum Heuristic {
-    Null,
-    Device(u64),
-    Size(u64),
-    Bytes(Vec<u8>),
-    Hash(Vec<u8>),
+pub struct HKey(pub Vec<u8>);
+
+#[derive(Clone)]
+#[derive(Debug)]
+#[derive(PartialOrd)]
+#[derive(Ord)]
+#[derive(PartialEq)]
+#[derive(Eq)]
+pub struct FSPath {
+    pub path      : String,
+    pub priority  : Priority,
+    pub protected : bool,
+}
+
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+#[derive(Default)]
+#[derive(PartialEq)]
+#[derive(Eq)]
+pub struct FileMeta {
+    pub mode  : u32,
+    pub uid   : u32,
+    pub gid   : u32,
+    pub mtime : (i64, i64),
 }
 
 #[derive(Clone)]
@@ -30,8 +59,35 @@ pub struct FSEntry {
     pub inode    : INode,
     pub size     : u64,
     pub priority : Priority,
-    pub paths    : Vec<String>,
-    pub hvalue   : Heuristic,
+    pub paths    : Vec<FSPath>,
+    pub hvalue   : Option<HKey>,
+    pub attrs    : Vec<HKey>,
+    pub meta     : FileMeta,
+    // Digest of the contents of a small file, see `small_file_key`
+    pub digest   : Option<HKey>,
+    // Physical offset of the first extent, `u64::MAX` if it is unknown.
+    // Queried by the first stage that reads in physical order.
+    pub offset   : Option<u64>,
+}
+
+impl FSPath {
+
+    pub fn new(path : String, priority : Priority, protected : bool) -> Self
+    {
+        FSPath { path, priority, protected }
+    }
+}
+
+impl FileMeta {
+
+    pub fn new(meta : &Metadata) -> Self {
+        FileMeta {
+            mode  : meta.mode(),
+            uid   : meta.uid(),
+            gid   : meta.gid(),
+            mtime : (meta.mtime(), meta.mtime_nsec()),
+        }
+    }
 }
 
 impl FSEntry {
@@ -41,49 +97,74 @@ impl FSEntry {
         inode    : INode,
         size     : u64,
         priority : Priority,
-        path     : String,
+        path     : FSPath,
     ) -> Self
     {
         FSEntry{
             dev, inode, size, priority,
-            paths : vec![path], hvalue : Heuristic::Null
+            paths  : vec![ path ],
+            hvalue : None,
+            attrs  : Vec::new(),
+            meta   : FileMeta::default(),
+            digest : None,
+            offset : None,
         }
     }
 
-    pub fn add_path(self : &mut Self, path : String) {
-        let path_exists = self.paths.iter().any( |x| { **x == path } );
+    pub fn add_path(&mut self, path : FSPath) {
+        self.priority = self.priority.min(path.priority);
 
-        if ! path_exists {
-            self.paths.push(path);
+        match self.paths.iter_mut().find( |x| { x.path == path.path } ) {
+            Some(fs_path) => {
+                fs_path.priority   = fs_path.priority.min(path.priority);
+                fs_path.protected |= path.protected;
+            },
+            None => {
+                self.paths.push(path);
+            },
         }
     }
+
+    pub fn is_protected(&self) -> bool {
+        self.paths.iter().any( |x| { x.protected } )
+    }
+
+    pub fn leader_path(&self) -> &FSPath {
+        let mut result = &self.paths[0];
+
+        for path in self.paths.iter().skip(1) {
+            if path.priority < result.priority {
+                result = path;
+            }
+        }
+
+        result
+    }
 }
 
-impl fmt::Debug for Heuristic {
+impl From<u32> for HKey {
 
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            Heuristic::Size(s)   => write!(f, "Size({})", s),
-            Heuristic::Device(d) => write!(f, "Dev({})", d),
-            Heuristic::Bytes(b)  => {
-                write!(f, "Bytes([ ")?;
+    fn from(value : u32) -> Self {
+        HKey(value.to_be_bytes().to_vec())
+    }
+}
 
-                for byte in b.iter() {
-                    write!(f, "{:02x} ", byte)?;
-                }
+impl From<u64> for HKey {
 
-                write!(f, "])")
-            },
-            Heuristic::Hash(h) => {
-                write!(f, "Hash(")?;
+    fn from(value : u64) -> Self {
+        HKey(value.to_be_bytes().to_vec())
+    }
+}
 
-                for byte in h.iter() {
-                    write!(f, "{:02x}", byte)?;
-                }
+impl fmt::Debug for HKey {
 
-                write!(f, ")")
-            },
-            Heuristic::Null => write!(f, "Null"),
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "HKey(")?;
+
+        for byte in self.0.iter() {
+            write!(f, "{:02x}", byte)?;
         }
+
+        write!(f, ")")
     }
 }
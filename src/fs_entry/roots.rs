@@ -0,0 +1,210 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use log::warn;
+
+use error::{Error, Result};
+use fs_entry::Priority;
+
+// Roots nested inside this one are skipped by the walker, so that each file is
+// scanned once and gets the priority of the innermost root containing it.
+// Files under protected roots are never removed or replaced.
+#[derive(Clone)]
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Eq)]
+pub struct ScanRoot {
+    pub path      : String,
+    pub priority  : Priority,
+    pub protected : bool,
+    pub nested    : Vec<PathBuf>,
+}
+
+impl ScanRoot {
+
+    pub fn new(path : String, priority : Priority, protected : bool) -> Self {
+        ScanRoot { path, priority, protected, nested : Vec::new() }
+    }
+}
+
+// Paths of entries are stored as strings, so roots must be valid UTF-8
+fn canonicalize_root(path : &str) -> Result<PathBuf> {
+    let result = fs::canonicalize(path).map_err(
+        |e| { Error::scan("resolve root", path, e) }
+    )?;
+
+    if result.to_str().is_none() {
+        return Err(Error::Config(format!(
+            "Root '{}' resolves to a path that is not valid UTF-8: {}",
+            path, result.display()
+        )));
+    }
+
+    Ok(result)
+}
+
+fn protect_nested_roots(roots : &mut [ScanRoot])
+{
+    let protected_paths : Vec<PathBuf> = roots.iter()
+        .filter(|x| { x.protected })
+        .map(   |x| { PathBuf::from(&x.path) })
+        .collect();
+
+    for root in roots.iter_mut().filter( |x| { ! x.protected } ) {
+        let root_path = Path::new(&root.path);
+
+        if let Some(p) = protected_paths.iter().find(
+            |x| { root_path.starts_with(x) }
+        )
+        {
+            warn!(
+                "Root '{}' is nested inside protected root '{}'. \
+                 Marking it as protected.",
+                root.path, p.display()
+            );
+            root.protected = true;
+        }
+    }
+}
+
+pub fn build_roots(paths : &[(PathBuf, bool)]) -> Vec<ScanRoot>
+{
+    let mut result : Vec<ScanRoot> = Vec::new();
+
+    for (idx, (path, protected)) in paths.iter().enumerate() {
+        let path_str = path.to_string_lossy().into_owned();
+
+        if let Some(root) = result.iter_mut().find(|x| { x.path == path_str })
+        {
+            warn!(
+                "Root '{}' is specified more than once. \
+                 Using priority of its first occurrence: {}",
+                path_str, root.priority
+            );
+            root.protected |= *protected;
+            continue;
+        }
+
+        result.push(ScanRoot::new(path_str, idx as Priority, *protected));
+    }
+
+    protect_nested_roots(&mut result);
+
+    let all_paths : Vec<PathBuf> =
+        result.iter().map( |x| { PathBuf::from(&x.path) } ).collect();
+
+    for root in result.iter_mut() {
+        let root_path = Path::new(&root.path);
+
+        for path in all_paths.iter() {
+            if (path != root_path) && path.starts_with(root_path) {
+                warn!(
+                    "Root '{}' is nested inside '{}'. It will be scanned \
+                     separately with its own priority.",
+                    path.display(), root.path
+                );
+                root.nested.push(path.clone());
+            }
+        }
+    }
+
+    result
+}
+
+// Protected roots are placed after the regular ones, so that indices of the
+// regular roots match their positions in `paths`.
+pub fn prepare_roots(paths : &[String], protected_paths : &[String])
+    -> Result<Vec<ScanRoot>>
+{
+    let mut canonical_paths : Vec<(PathBuf, bool)> =
+        Vec::with_capacity(paths.len() + protected_paths.len());
+
+    for path in paths.iter() {
+        canonical_paths.push((canonicalize_root(path)?, false));
+    }
+
+    for path in protected_paths.iter() {
+        canonical_paths.push((canonicalize_root(path)?, true));
+    }
+
+    Ok(build_roots(&canonical_paths))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn to_paths(paths : &[&str]) -> Vec<PathBuf> {
+        paths.iter().map( |x| { PathBuf::from(x) } ).collect()
+    }
+
+    fn to_roots(paths : &[&str], protected : &[&str]) -> Vec<(PathBuf, bool)>
+    {
+        let mut result : Vec<(PathBuf, bool)> = Vec::new();
+
+        result.extend(paths    .iter().map( |x| { (PathBuf::from(x), false) }));
+        result.extend(protected.iter().map( |x| { (PathBuf::from(x), true)  }));
+
+        result
+    }
+
+    #[test]
+    fn test_build_roots_disjoint() {
+        let roots = build_roots(&to_roots(&[ "/a", "/b" ], &[]));
+
+        assert_eq!(
+            roots,
+            vec![
+                ScanRoot::new("/a".to_string(), 0, false),
+                ScanRoot::new("/b".to_string(), 1, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_build_roots_repeated() {
+        let roots = build_roots(&to_roots(&[ "/a", "/b", "/a" ], &[]));
+
+        assert_eq!(
+            roots,
+            vec![
+                ScanRoot::new("/a".to_string(), 0, false),
+                ScanRoot::new("/b".to_string(), 1, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_build_roots_nested() {
+        let roots = build_roots(&to_roots(&[ "/a/b/c", "/a", "/a/b" ], &[]));
+
+        assert_eq!(roots.len(), 3);
+        assert_eq!(roots[0].nested, to_paths(&[]));
+        assert_eq!(roots[1].nested, to_paths(&[ "/a/b/c", "/a/b" ]));
+        assert_eq!(roots[2].nested, to_paths(&[ "/a/b/c" ]));
+        assert_eq!(roots[2].priority, 2);
+    }
+
+    #[test]
+    fn test_build_roots_common_prefix_not_nested() {
+        let roots = build_roots(&to_roots(&[ "/a/b", "/a/bc" ], &[]));
+
+        assert!(roots[0].nested.is_empty());
+        assert!(roots[1].nested.is_empty());
+    }
+
+    #[test]
+    fn test_build_roots_protected() {
+        let roots = build_roots(
+            &to_roots(&[ "/a", "/b/c", "/d" ], &[ "/b", "/a" ])
+        );
+
+        assert_eq!(roots.len(), 4);
+        assert!(  roots[0].protected);
+        assert!(  roots[1].protected);
+        assert!(! roots[2].protected);
+        assert!(  roots[3].protected);
+        assert_eq!(roots[3].path, "/b");
+        assert_eq!(roots[3].nested, to_paths(&[ "/b/c" ]));
+    }
+}
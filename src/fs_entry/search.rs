@@ -1,33 +1,40 @@
 use std::collections::HashMap;
 use std::fs::{self, DirEntry, Metadata};
-use std::io;
 use std::os::unix::prelude::*;
 use std::path::Path;
 
-use log::warn;
+use log::{debug, warn};
 
-use fs_entry::{FSEntry, Dev, INode, Priority};
+use error::{Error, Result};
+use fs_entry::{
+    FSEntry, FSPath, FileMeta, Dev, INode, ScanRoot, ScanConfig
+};
 use fs_entry::search_state::SearchState;
+use utils::interrupt::Interrupt;
+use utils::progress::{ProgressEvent, ProgressSink};
 
 fn add_path_to_entry(
-    path     : &Path,
-    meta     : &Metadata,
-    priority : Priority,
-    files    : &mut HashMap<(Dev, INode), FSEntry>,
+    path  : &Path,
+    meta  : &Metadata,
+    state : &SearchState,
+    files : &mut HashMap<(Dev, INode), FSEntry>,
 )
 {
     let inode : INode  = meta.ino();
     let dev   : Dev    = meta.dev();
-    let path  : String = path.to_str().unwrap().to_string();
+    let path  : FSPath = FSPath::new(
+        path.to_str().unwrap().to_string(), state.priority, state.protected
+    );
 
     match files.get_mut(&(dev, inode)) {
         Some(fs_entry) => fs_entry.add_path(path),
         None           => {
-            let size : u64 = meta.size();
-            files.insert(
-                (dev, inode),
-                FSEntry::new(dev, inode, size, priority, path)
+            let mut entry = FSEntry::new(
+                dev, inode, meta.size(), state.priority, path
             );
+            entry.meta = FileMeta::new(meta);
+
+            files.insert((dev, inode), entry);
         },
     }
 }
@@ -54,6 +61,10 @@ fn check_dir_entry(
         return false;
     }
 
+    if ! state.passes_fstype(&path, meta) {
+        return false;
+    }
+
     state.tick(&path);
 
     true
@@ -63,27 +74,37 @@ fn recurse_into_directory(
     path  : &Path,
     files : &mut HashMap<(Dev, INode), FSEntry>,
     state : &mut SearchState,
-) -> io::Result<()>
+) -> Result<()>
 {
     if ! path.is_dir() {
         return Ok(());
     }
 
     for dir_entry in verbose_question_mark!(
-        fs::read_dir(path),
-        state, format!("Failed to read directory: {}", path.display())
+        fs::read_dir(path).map_err(
+            |e| { Error::scan("read directory", path, e) }
+        ),
+        state
     )
     {
+        if state.interrupt.is_set() {
+            break;
+        }
+
         let entry = sloppy_unwrap_or_continue!(
-            dir_entry, state,
-            format!("Failed to read directory entry in: {}", path.display())
+            dir_entry.map_err(
+                |e| { Error::scan("read directory entry in", path, e) }
+            ),
+            state
         );
 
         let entry_path = entry.path();
 
         let meta  = sloppy_unwrap_or_continue!(
-            entry.metadata(),
-            state, format!("Failed to stat entry: {}", entry_path.display())
+            entry.metadata().map_err(
+                |e| { Error::scan("stat entry", &entry_path, e) }
+            ),
+            state
         );
 
         if ! check_dir_entry(&entry, &meta, state) {
@@ -91,11 +112,18 @@ fn recurse_into_directory(
         }
 
         if entry_path.is_file() {
-            add_path_to_entry(&entry_path, &meta, state.priority, files);
+            add_path_to_entry(&entry_path, &meta, state, files);
         }
         else if entry_path.is_dir() {
-            sloppy_unwrap_or_continue!(
-                recurse_into_directory(&entry_path, files, state), state, ""
+            if state.is_nested_root(&entry_path) {
+                debug!(
+                    "Skipping nested root: {}", entry_path.display()
+                );
+                continue;
+            }
+
+            quiet_unwrap_or_continue!(
+                recurse_into_directory(&entry_path, files, state), state
             );
         }
     }
@@ -103,33 +131,31 @@ fn recurse_into_directory(
     Ok(())
 }
 
-#[allow(clippy::too_many_arguments)]
 pub fn collect_files(
-    root           : &str,
-    files_map      : &mut HashMap<(Dev, INode), FSEntry>,
-    abort_on_error : bool,
-    verbose        : bool,
-    one_fs         : bool,
-    inc_patterns   : &[String],
-    ex_patterns    : &[String],
-    min_file_size  : Option<u64>,
-    max_file_size  : Option<u64>,
-    priority       : Priority,
-) -> io::Result<()>
+    root      : &ScanRoot,
+    files_map : &mut HashMap<(Dev, INode), FSEntry>,
+    config    : &ScanConfig,
+    progress  : &dyn ProgressSink,
+    interrupt : &Interrupt,
+) -> Result<()>
 {
-    let path  = Path::new(root);
-    let mut state = SearchState::new(
-        path, abort_on_error, verbose, one_fs, inc_patterns, ex_patterns,
-        min_file_size, max_file_size, priority
-    )?;
-
-    let result = recurse_into_directory(
-        Path::new(root), files_map, &mut state
+    let path  = Path::new(&root.path);
+    let mut state = SearchState::new(root, config, progress, interrupt)?;
+
+    let root_meta = verbose_question_mark!(
+        fs::metadata(path).map_err( |e| { Error::scan("stat root", path, e) } ),
+        state
     );
 
-    state.finish();
+    if ! state.passes_fstype(path, &root_meta) {
+        return Ok(());
+    }
+
+    progress.event(&ProgressEvent::ScanStart { root : &root.path });
+    let result = recurse_into_directory(path, files_map, &mut state);
+    progress.event(&ProgressEvent::ScanFinish { root : &root.path });
 
-    if abort_on_error {
+    if config.abort_on_error {
         return result;
     }
 
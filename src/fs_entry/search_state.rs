@@ -1,28 +1,33 @@
 use std::fs::{self, Metadata};
-use std::io;
 use std::os::unix::prelude::*;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use globset::{GlobBuilder, GlobSetBuilder, GlobSet};
-use indicatif::{ProgressBar, ProgressStyle};
 
-use fs_entry::{Dev, Priority};
+use error::{Error, Result};
+use fs_entry::{Dev, Priority, ScanRoot, ScanConfig};
+use fs_entry::fstype::FSTypeFilter;
+use utils::interrupt::Interrupt;
+use utils::progress::{ProgressEvent, ProgressSink};
 
-pub struct SearchState {
+pub struct SearchState<'a> {
     pub abort_on_error : bool,
-    pub verbose  : bool,
-    pub one_fs   : bool,
-    pub dev      : Option<Dev>,
-    pub spinner  : Option<ProgressBar>,
-    pub excludes : Option<GlobSet>,
-    pub includes : Option<GlobSet>,
-    pub min_size : Option<u64>,
-    pub max_size : Option<u64>,
-    pub priority : Priority,
+    pub one_fs    : bool,
+    pub dev       : Option<Dev>,
+    pub fstypes   : Option<FSTypeFilter>,
+    pub progress  : &'a dyn ProgressSink,
+    pub interrupt : &'a Interrupt,
+    pub excludes  : Option<GlobSet>,
+    pub includes  : Option<GlobSet>,
+    pub min_size  : Option<u64>,
+    pub max_size  : Option<u64>,
+    pub priority  : Priority,
+    pub protected : bool,
+    pub nested    : Vec<PathBuf>,
 }
 
 fn build_glob_set_impl(patterns : &[String])
-    -> Result<Option<GlobSet>, globset::Error>
+    -> std::result::Result<Option<GlobSet>, globset::Error>
 {
     if patterns.is_empty() {
         return Ok(None);
@@ -47,52 +52,51 @@ fn build_glob_set_impl(patterns : &[String])
     builder.build().map( |r| { Some(r) } )
 }
 
-fn build_glob_set(patterns : &[String]) -> io::Result<Option<GlobSet>>
+fn build_glob_set(patterns : &[String]) -> Result<Option<GlobSet>>
 {
     build_glob_set_impl(patterns).map_err(
-        | x | { io::Error::new(io::ErrorKind::Other, x.to_string() ) }
+        | x | {
+            Error::Filter {
+                pattern : x.glob().unwrap_or_default().to_string(),
+                message : x.kind().to_string(),
+            }
+        }
     )
 }
 
-impl SearchState {
+impl<'a> SearchState<'a> {
 
-    #[allow(clippy::too_many_arguments)]
     pub fn new(
-        path           : &Path,
-        abort_on_error : bool,
-        verbose        : bool,
-        one_fs         : bool,
-        inc_patterns   : &[String],
-        ex_patterns    : &[String],
-        min_size       : Option<u64>,
-        max_size       : Option<u64>,
-        priority       : Priority,
-    ) -> io::Result<Self> {
-
-        let mut dev     : Option<u64> = None;
-        let mut spinner : Option<ProgressBar> = None;
-
-        if one_fs {
-            let metadata = fs::metadata(path)?;
+        root      : &ScanRoot,
+        config    : &ScanConfig,
+        progress  : &'a dyn ProgressSink,
+        interrupt : &'a Interrupt,
+    ) -> Result<Self> {
+
+        let mut dev : Option<u64> = None;
+
+        if config.one_file_system {
+            let metadata = fs::metadata(&root.path).map_err(
+                |e| { Error::scan("stat root", &root.path, e) }
+            )?;
             dev = Some(metadata.dev());
         }
 
-        if verbose {
-            let s = ProgressBar::new_spinner();
-            s.set_style(ProgressStyle::default_bar()
-                .template("[{elapsed}] {spinner} {prefix} {wide_msg}")
-            );
-            s.set_prefix("Scanning");
-
-            spinner = Some(s);
-        }
-
-        let includes = build_glob_set(inc_patterns)?;
-        let excludes = build_glob_set(ex_patterns)?;
+        let includes = build_glob_set(&config.includes)?;
+        let excludes = build_glob_set(&config.excludes)?;
+        let fstypes  = FSTypeFilter::new(
+            &config.skip_fstypes, &config.only_fstypes
+        )?;
 
         Ok(Self {
-            abort_on_error, verbose, one_fs, dev, spinner, includes, excludes,
-            min_size, max_size, priority
+            dev, fstypes, progress, interrupt, includes, excludes,
+            abort_on_error : config.abort_on_error,
+            one_fs         : config.one_file_system,
+            min_size       : config.min_file_size,
+            max_size       : config.max_file_size,
+            priority       : root.priority,
+            protected      : root.protected,
+            nested         : root.nested.clone(),
         })
     }
 
@@ -126,16 +130,19 @@ impl SearchState {
         true
     }
 
-    pub fn tick(&mut self, path : &Path) {
-        if let Some(s) = &self.spinner {
-            s.set_message(path.to_str().unwrap());
+    pub fn passes_fstype(&mut self, path : &Path, meta : &Metadata) -> bool {
+        match self.fstypes {
+            Some(ref mut f) => f.accepts(meta.dev(), path),
+            None            => true,
         }
     }
 
-    pub fn finish(&self) {
-        if let Some(s) = &self.spinner {
-            s.finish_with_message("Done");
-        }
+    pub fn is_nested_root(&self, path : &Path) -> bool {
+        self.nested.iter().any( |x| { x == path } )
+    }
+
+    pub fn tick(&mut self, path : &Path) {
+        self.progress.event(&ProgressEvent::ScanEntry { path });
     }
 }
 
@@ -1,133 +1,72 @@
 extern crate clap;
+extern crate blake3;
 extern crate crypto_hash;
+extern crate ctrlc;
 extern crate env_logger;
 extern crate globset;
 extern crate humanize_rs;
 extern crate indicatif;
+extern crate libc;
 extern crate fastrand;
 #[macro_use] extern crate log;
+extern crate xxhash_rust;
 
-use std::collections::HashMap;
 use std::fs::File;
 use std::io::{self, Write};
 
-use crypto_hash::Algorithm;
 use env_logger::Env;
 use indicatif::HumanBytes;
 use log::{info, Level};
 
 #[macro_use] mod err_macro;
+pub mod error;
 pub mod args;
 pub mod fs_entry;
 pub mod dups;
 pub mod utils;
-
-use args::{Args, DedupAction};
-use fs_entry::{FSEntry, Dev, INode, Priority, collect_files};
-use dups::search::{
-    remove_unique_entries_by_heuristic_fn, remove_unique_entries_by_heuristic,
-    group_by_heuristic
+pub mod deduplicator;
+
+pub use deduplicator::{Deduplicator, DedupReport, DuplicateStats};
+pub use error::{Error, Result};
+pub use fs_entry::ScanConfig;
+
+use args::{Args, DedupAction, ProgressFormat};
+use fs_entry::FSEntry;
+use dups::dedup::DedupSummary;
+use dups::hasher::HashAlgorithm;
+use dups::journal::undo_journal;
+use utils::interrupt::{Interrupt, EXIT_INTERRUPTED};
+use utils::reader::set_idle_io_priority;
+use utils::error_log::{
+    ErrorLog, SkippedError, count_by_category, write_error_report
 };
-use dups::heuristics::{
-    HeuristicFn, fn_first_bytes, fn_last_bytes, fn_file_hash
+use utils::progress::{
+    IndicatifProgress, JsonProgress, NullProgress, ProgressSink
 };
-use dups::dedup::deduplicate;
-
-fn collect_all_files(args : &Args) -> io::Result<Vec<FSEntry>> {
-    let mut files_map : HashMap<(Dev, INode), FSEntry> = HashMap::new();
-
-    for (idx,path) in args.paths.iter().enumerate() {
-        info!("Scanning '{}' for entries...", path);
-        collect_files(
-            &path, &mut files_map, args.abort_on_error, args.show_progress,
-            args.one_file_system, &args.includes, &args.excludes,
-            args.min_file_size, args.max_file_size, idx as Priority
-        )?;
-    }
-
-    Ok(files_map.into_iter().map( | (_, v) | { v } ).collect())
-}
-
-fn log_possible_duplicates(entries : &[FSEntry], name : &str) {
-    if log_enabled!(Level::Info) {
-        let n_entries = entries.len();
-        let n_files : usize = entries.iter().map( |x| x.paths.len() ).sum();
-
-        info!(
-            "Possibly identical files after grouping by {}: {} (inodes: {}).",
-            name, n_files, n_entries
-        );
-    }
-
-    trace!("Entries: {:#?}", entries);
-}
-
-fn remove_unique_by_fn_wrap(
-    entries : Vec<FSEntry>, args : &Args, name : &str, func : Box<HeuristicFn>
-) -> io::Result<Vec<FSEntry>>
-{
-    info!("Grouping entries by {}", name);
-
-    let result = remove_unique_entries_by_heuristic_fn(
-        entries,
-        args.action == DedupAction::Hardlink,
-        format!("Grouping by {}", name).as_str(),
-        args.show_progress,
-        args.abort_on_error,
-        func
-    );
-
-    match &result {
-        Ok(r)  => { log_possible_duplicates(r, name); },
-        Err(e) => { warn!("Grouping by {} failed: {}", name, e); },
-    }
 
-    result
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub enum RunStatus {
+    NoDuplicates, DuplicatesFound, Deduplicated, Partial, Interrupted,
+    Restored,
 }
 
-fn remove_unique_files_by_size(entries : Vec<FSEntry>, args : &Args)
-    -> Vec<FSEntry>
-{
-    let result = remove_unique_entries_by_heuristic(
-        entries, args.action == DedupAction::Hardlink
-    );
-
-    log_possible_duplicates(&result, "file size");
-
-    result
-}
-
-fn remove_unique_files_by_bytes(entries : Vec<FSEntry>, args : &Args)
-    -> io::Result<Vec<FSEntry>>
-{
-    let n_read = args.n_read;
-
-    let result = remove_unique_by_fn_wrap(
-        entries, args, "first bytes",
-        Box::new(move | entries | { fn_first_bytes(entries, n_read) })
-    )?;
+pub const EXIT_FATAL : i32 = 1;
 
-    remove_unique_by_fn_wrap(
-        result, args, "last bytes",
-        Box::new(move | entries | { fn_last_bytes(entries, n_read) })
-    )
-}
+impl RunStatus {
 
-pub fn remove_unique_files(entries : Vec<FSEntry>, args : &Args)
-    -> io::Result<Vec<FSEntry>>
-{
-    info!("Grouping entries by size");
-    let mut result = remove_unique_files_by_size(entries, args);
-
-    if args.n_read > 0 {
-        result = remove_unique_files_by_bytes(result, args)?;
+    pub fn exit_code(self) -> i32 {
+        match self {
+            RunStatus::NoDuplicates    => 0,
+            RunStatus::Restored        => 0,
+            RunStatus::DuplicatesFound => 2,
+            RunStatus::Deduplicated    => 3,
+            RunStatus::Partial         => 4,
+            RunStatus::Interrupted     => EXIT_INTERRUPTED,
+        }
     }
-
-    let algo = algo_from_str(&args.hash)?;
-    remove_unique_by_fn_wrap(
-        result, args, &format!("hash ({})", str::to_uppercase(&args.hash)),
-        Box::new(move | entries | { fn_file_hash(entries, algo) })
-    )
 }
 
 fn setup_logging(args : &Args)
@@ -140,58 +79,35 @@ fn setup_logging(args : &Args)
     builder.init()
 }
 
-fn print_initial_stats(entries : &[FSEntry])
-{
-    if log_enabled!(Level::Info) {
-        let n_inodes = entries.len();
-        let n_files : usize = entries.iter().map( |x| x.paths.len() ).sum();
-        info!("Found {} entries (inodes: {})", n_files, n_inodes);
-    }
-}
-
-fn algo_from_str(s : &str) -> io::Result<Algorithm>
+fn algo_from_str(s : &str) -> Result<HashAlgorithm>
 {
     match s {
-        "md5"    => Ok(Algorithm::MD5),
-        "sha1"   => Ok(Algorithm::SHA1),
-        "sha256" => Ok(Algorithm::SHA256),
-        "sha512" => Ok(Algorithm::SHA512),
-        _        => Err(io::Error::new(
-            io::ErrorKind::Other, format!("Cannot parse alogorithm: {}", s)
+        "md5"    => Ok(HashAlgorithm::Md5),
+        "sha1"   => Ok(HashAlgorithm::Sha1),
+        "sha256" => Ok(HashAlgorithm::Sha256),
+        "sha512" => Ok(HashAlgorithm::Sha512),
+        "blake3" => Ok(HashAlgorithm::Blake3),
+        "xxh3"   => Ok(HashAlgorithm::Xxh3),
+        _        => Err(Error::Config(
+            format!("Cannot parse alogorithm: {}", s)
         )),
     }
 }
 
-fn print_final_stats(duplicate_groups : &[Vec<FSEntry>]) {
+fn print_final_stats(stats : &DuplicateStats) {
 
     if ! log_enabled!(Level::Info) {
         return;
     }
 
-    let mut n_dupl_inodes : usize = 0;
-    let mut n_dupl_files  : usize = 0;
-    let mut n_inodes      : usize = 0;
-    let mut saved_size    : u64   = 0;
-
-    for group in duplicate_groups.iter() {
-        let group_size : usize = group.len();
-
-        n_inodes      += group_size;
-        n_dupl_inodes += group_size - 1;
-        n_dupl_files  += group.iter().skip(1).map(
-            |x| x.paths.len()
-        ).sum::<usize>();
-
-        saved_size += ((group_size - 1) as u64) * group[0].size;
-    }
-
-    let mult = (n_inodes as f32) / ((n_inodes - n_dupl_inodes) as f32);
+    let mult = ((stats.n_dupl_inodes + stats.n_groups) as f32)
+             / (stats.n_groups as f32);
 
     info!(
         "Found {} duplicate files (inodes: {}). Avr. Mult: {:.2}",
-        n_dupl_files, n_dupl_inodes, mult
+        stats.n_dupl_files, stats.n_dupl_inodes, mult
     );
-    info!("Deduplication will save {}", HumanBytes(saved_size));
+    info!("Deduplication will save {}", HumanBytes(stats.saved_size));
 }
 
 fn write_path_to_results_file(file : &mut File, path : &str) -> io::Result<()>
@@ -210,28 +126,19 @@ fn write_path_to_results_file(file : &mut File, path : &str) -> io::Result<()>
     Ok(())
 }
 
-fn print_results_file(
-    duplicate_groups : &[Vec<FSEntry>], path : &Option<String>
+fn write_results_file(
+    file : &mut File, duplicate_groups : &[Vec<FSEntry>], title : &str
 ) -> io::Result<()>
 {
-    if duplicate_groups.is_empty() || path.is_none() {
-        return Ok(());
-    }
-
-    let path : String = path.as_ref().unwrap().clone();
-    info!("Saving duplicate entries to {}", path);
-
-    let mut file = File::create(&path)?;
-
     for group in duplicate_groups.iter() {
         let head = &group[0];
-        writeln!(file, "Identical Files. Size: {}", head.size)?;
+        writeln!(file, "{}. Size: {}", title, head.size)?;
 
         for entry in group.iter() {
             writeln!(file, "  {} {}", entry.dev, entry.inode)?;
 
             for path in entry.paths.iter() {
-                write_path_to_results_file(&mut file, path)?;
+                write_path_to_results_file(file, &path.path)?;
             }
         }
     }
@@ -239,36 +146,254 @@ fn print_results_file(
     Ok(())
 }
 
-pub fn run() -> io::Result<()>
+fn print_results_file(
+    duplicate_groups : &[Vec<FSEntry>], path : &Option<String>, title : &str
+) -> Result<()>
+{
+    if duplicate_groups.is_empty() || path.is_none() {
+        return Ok(());
+    }
+
+    let path : String = path.as_ref().unwrap().clone();
+    info!("Saving duplicate entries to {}", path);
+
+    let result = File::create(&path).and_then(
+        |mut file| { write_results_file(&mut file, duplicate_groups, title) }
+    );
+
+    result.map_err( |e| { Error::output(&path, e) } )
+}
+
+fn print_dedup_summary(summary : &DedupSummary) {
+    if summary.interrupted {
+        warn!(
+            "Deduplication interrupted after {} files. Finished {} of {} \
+            groups, remaining files were left untouched",
+            summary.n_done_files, summary.done_groups.len(), summary.n_groups
+        );
+    }
+    else {
+        info!(
+            "Deduplicated {} files in {} groups",
+            summary.n_done_files, summary.done_groups.len()
+        );
+    }
+}
+
+fn print_error_summary(errors : &[SkippedError]) {
+    if errors.is_empty() {
+        return;
+    }
+
+    warn!("Skipped {} entries due to errors:", errors.len());
+
+    for (category, count) in count_by_category(errors).iter() {
+        warn!("  {:>6}  {}", count, category);
+    }
+}
+
+fn save_error_report(errors : &[SkippedError], path : &Option<String>)
+    -> Result<()>
+{
+    match path {
+        Some(path) => {
+            info!("Saving error report to {}", path);
+            write_error_report(path, errors).map_err(
+                |e| { Error::output(path, e) }
+            )
+        },
+        None => Ok(()),
+    }
+}
+
+fn progress_from_args(args : &Args) -> Box<dyn ProgressSink>
+{
+    match args.progress {
+        ProgressFormat::None => Box::new(NullProgress),
+        ProgressFormat::Bar  => Box::new(IndicatifProgress::new()),
+        ProgressFormat::Json => Box::new(JsonProgress::new(io::stderr())),
+    }
+}
+
+fn deduplicator_from_args(args : &Args) -> Result<Deduplicator>
+{
+    let scan = ScanConfig {
+        roots           : args.paths.clone(),
+        references      : args.references.clone(),
+        includes        : args.includes.clone(),
+        excludes        : args.excludes.clone(),
+        one_file_system : args.one_file_system,
+        skip_fstypes    : args.skip_fstypes.clone(),
+        only_fstypes    : args.only_fstypes.clone(),
+        min_file_size   : args.min_file_size,
+        max_file_size   : args.max_file_size,
+        abort_on_error  : args.abort_on_error,
+    };
+
+    let mut result = Deduplicator::new(scan)
+        .n_read(args.n_read)
+        .n_samples(args.n_samples)
+        .compare_max(args.compare_max)
+        .io_hints(args.io_hints)
+        .max_read_rate(args.max_read_rate)
+        .read_order(args.read_order)
+        .small_size(args.small_size)
+        .hash(algo_from_str(&args.hash)?)
+        .action(args.action)
+        .dry_run(args.dry_run)
+        .plan_output(Box::new(io::stdout()))
+        .root_span(args.root_span)
+        .metadata_policy(args.metadata)
+        .stages(&args.stages)
+        .progress(progress_from_args(args));
+
+    if let Some(path) = &args.journal {
+        result = result.journal(path);
+    }
+
+    for root in args.with_roots.iter() {
+        result = result.with_root(*root);
+    }
+
+    for attr in args.same.iter() {
+        result = result.require_same(*attr);
+    }
+
+    Ok(result)
+}
+
+fn run_undo(args : &Args, journal : &str) -> Result<RunStatus>
+{
+    let progress = ErrorLog::new(progress_from_args(args));
+
+    let summary = undo_journal(
+        journal, args.abort_on_error, args.dry_run, &progress,
+        &mut io::stdout()
+    )?;
+
+    info!(
+        "Restored {} of {} journaled files, skipped {}",
+        summary.n_restored, summary.n_records, summary.n_skipped
+    );
+
+    let errors = progress.errors();
+    print_error_summary(&errors);
+    save_error_report(&errors, &args.error_report)?;
+
+    if (summary.n_skipped > 0) || ! errors.is_empty() {
+        return Ok(RunStatus::Partial);
+    }
+
+    Ok(RunStatus::Restored)
+}
+
+pub fn run() -> Result<RunStatus>
 {
     let args = Args::parse();
     setup_logging(&args);
 
+    if let Some(journal) = &args.undo {
+        return run_undo(&args, journal);
+    }
+
+    args.check()?;
+
     if (args.action == DedupAction::Print) && args.result_path.is_none() {
-        return Err(io::Error::new(
-            io::ErrorKind::Other,
+        return Err(Error::Config(
             "No output file specified for action 'print'".to_string()
         ));
     }
 
-    let entries : Vec<FSEntry> = collect_all_files(&args)?;
-    print_initial_stats(&entries);
+    let interrupt = Interrupt::new();
+    interrupt.install_handler()?;
 
-    let entries = remove_unique_files(entries, &args)?;
-    let mut duplicate_groups = group_by_heuristic(
-        entries, args.action == DedupAction::Hardlink
-    );
+    if args.idle_io {
+        if let Err(e) = set_idle_io_priority() {
+            warn!("Failed to set idle I/O priority: {}", e);
+        }
+    }
 
-    duplicate_groups.sort_by( |a, b| a[0].size.cmp(&b[0].size) );
+    let dedup  = deduplicator_from_args(&args)?.interrupt(interrupt.clone());
+    let report = dedup.search()?;
 
-    print_final_stats(&duplicate_groups);
-    print_results_file(&duplicate_groups, &args.result_path)?;
+    // Dry runs only plan the operations, nothing is changed
+    let mut n_done_files : usize = 0;
 
-    deduplicate(
-        &duplicate_groups, args.action, args.abort_on_error,
-        args.dry_run, args.show_progress
-    )?;
+    if ! report.complete {
+        warn!("Search interrupted, no files were deduplicated");
 
-    Ok(())
+        if args.write_partial {
+            print_results_file(
+                &report.groups, &args.result_path, "Possibly Identical Files"
+            )?;
+        }
+    }
+    else {
+        print_final_stats(&report.stats);
+        print_results_file(
+            &report.groups, &args.result_path, "Identical Files"
+        )?;
+
+        if let Some(path) = &args.emit_script {
+            info!("Saving deduplication script to {}", path);
+            dedup.emit_script(&report.groups, path)?;
+        }
+        else if args.action != DedupAction::Print {
+            let summary = dedup.deduplicate(&report.groups)?;
+            print_dedup_summary(&summary);
+
+            if ! args.dry_run {
+                n_done_files = summary.n_done_files;
+            }
+        }
+    }
+
+    let errors = dedup.skipped_errors();
+    print_error_summary(&errors);
+    save_error_report(&errors, &args.error_report)?;
+
+    Ok(run_status(
+        interrupt.is_set(), errors.len(), report.groups.len(), n_done_files
+    ))
 }
 
+fn run_status(
+    interrupted  : bool,
+    n_errors     : usize,
+    n_groups     : usize,
+    n_done_files : usize,
+) -> RunStatus
+{
+    if interrupted {
+        return RunStatus::Interrupted;
+    }
+
+    if n_errors > 0 {
+        return RunStatus::Partial;
+    }
+
+    if n_groups == 0 {
+        return RunStatus::NoDuplicates;
+    }
+
+    if n_done_files == 0 {
+        return RunStatus::DuplicatesFound;
+    }
+
+    RunStatus::Deduplicated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run_status() {
+        assert_eq!(run_status(false, 0, 0, 0).exit_code(), 0);
+        assert_eq!(run_status(false, 0, 2, 0).exit_code(), 2);
+        assert_eq!(run_status(false, 0, 2, 3).exit_code(), 3);
+        assert_eq!(run_status(false, 1, 2, 3).exit_code(), 4);
+        assert_eq!(run_status(false, 1, 2, 0).exit_code(), 4);
+        assert_eq!(run_status(true,  1, 2, 3), RunStatus::Interrupted);
+    }
+}
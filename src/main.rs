@@ -3,10 +3,12 @@ use std::process;
 
 fn main()
 {
-    if let Err(e) = fdedup::run() {
-        println!("Application error: {}", e);
+    match fdedup::run() {
+        Ok(status) => process::exit(status.exit_code()),
+        Err(e)     => {
+            println!("Application error: {}", e);
 
-        process::exit(1);
+            process::exit(fdedup::EXIT_FATAL);
+        },
     }
 }
-
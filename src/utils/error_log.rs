@@ -0,0 +1,146 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Write};
+
+use error::Error;
+use utils::progress::{ProgressEvent, ProgressSink, json_escape};
+
+#[derive(Clone)]
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub struct SkippedError {
+    pub stage   : String,
+    pub path    : Option<String>,
+    pub kind    : Option<io::ErrorKind>,
+    pub message : String,
+}
+
+// Forwards all events to the wrapped sink and keeps every reported error, so
+// that errors skipped in sloppy mode can be summarized after the run.
+pub struct ErrorLog {
+    sink   : Box<dyn ProgressSink>,
+    errors : RefCell<Vec<SkippedError>>,
+}
+
+impl SkippedError {
+
+    pub fn new(error : &Error) -> Self {
+        SkippedError {
+            stage   : error.stage().to_string(),
+            path    : error.path().map( |x| { x.to_string() } ),
+            kind    : error.kind(),
+            message : error.to_string(),
+        }
+    }
+
+    pub fn category(&self) -> String {
+        match self.kind {
+            Some(kind) => format!("{}: {}", self.stage, kind),
+            None       => format!("{}: other", self.stage),
+        }
+    }
+}
+
+impl ErrorLog {
+
+    pub fn new(sink : Box<dyn ProgressSink>) -> Self {
+        ErrorLog { sink, errors : RefCell::new(Vec::new()) }
+    }
+
+    pub fn errors(&self) -> Vec<SkippedError> {
+        self.errors.borrow().clone()
+    }
+}
+
+impl ProgressSink for ErrorLog {
+
+    fn event(&self, event : &ProgressEvent) {
+        if let ProgressEvent::Error { error } = event {
+            self.errors.borrow_mut().push(SkippedError::new(error));
+        }
+
+        self.sink.event(event);
+    }
+}
+
+pub fn count_by_category(errors : &[SkippedError]) -> BTreeMap<String, usize>
+{
+    let mut result : BTreeMap<String, usize> = BTreeMap::new();
+
+    for error in errors.iter() {
+        *result.entry(error.category()).or_insert(0) += 1;
+    }
+
+    result
+}
+
+fn write_error(file : &mut File, error : &SkippedError) -> io::Result<()>
+{
+    writeln!(
+        file,
+        "{{\"stage\":{},\"kind\":{},\"path\":{},\"message\":{}}}",
+        json_escape(&error.stage),
+        error.kind.map_or(
+            "null".to_string(), |x| { json_escape(&x.to_string()) }
+        ),
+        error.path.as_ref().map_or(
+            "null".to_string(), |x| { json_escape(x) }
+        ),
+        json_escape(&error.message)
+    )
+}
+
+pub fn write_error_report(path : &str, errors : &[SkippedError])
+    -> io::Result<()>
+{
+    let mut file = File::create(path)?;
+
+    for error in errors.iter() {
+        write_error(&mut file, error)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use utils::progress::NullProgress;
+
+    #[test]
+    fn test_error_log() {
+        let log = ErrorLog::new(Box::new(NullProgress));
+
+        let errors = [
+            Error::scan("stat entry", "/a", io::ErrorKind::NotFound.into()),
+            Error::scan("stat entry", "/b", io::ErrorKind::NotFound.into()),
+            Error::dedup(
+                "remove file", "/c", io::ErrorKind::PermissionDenied.into()
+            ),
+        ];
+
+        log.event(&ProgressEvent::StageTick { name : "hash", path : None });
+
+        for error in errors.iter() {
+            log.event(&ProgressEvent::Error { error });
+        }
+
+        let skipped = log.errors();
+
+        assert_eq!(skipped.len(), 3);
+        assert_eq!(skipped[2].path, Some("/c".to_string()));
+        assert_eq!(skipped[2].kind, Some(io::ErrorKind::PermissionDenied));
+
+        let counts : Vec<(String, usize)> =
+            count_by_category(&skipped).into_iter().collect();
+
+        assert_eq!(
+            counts,
+            vec![
+                (format!("dedup: {}", io::ErrorKind::PermissionDenied), 1),
+                (format!("scan: {}",  io::ErrorKind::NotFound),         2),
+            ]
+        );
+    }
+}
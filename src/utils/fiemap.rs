@@ -0,0 +1,66 @@
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+
+// _IOWR('f', 11, struct fiemap)
+const FS_IOC_FIEMAP : libc::c_ulong = 0xC020_660B;
+
+// Location of the extent is not known (yet)
+const FIEMAP_EXTENT_UNKNOWN  : u32 = 0x0000_0002;
+const FIEMAP_EXTENT_DELALLOC : u32 = 0x0000_0004;
+
+#[repr(C)]
+#[derive(Default)]
+struct FiemapExtent {
+    fe_logical    : u64,
+    fe_physical   : u64,
+    fe_length     : u64,
+    fe_reserved64 : [u64; 2],
+    fe_flags      : u32,
+    fe_reserved   : [u32; 3],
+}
+
+// Header of the request followed by room for a single extent
+#[repr(C)]
+#[derive(Default)]
+struct Fiemap {
+    fm_start          : u64,
+    fm_length         : u64,
+    fm_flags          : u32,
+    fm_mapped_extents : u32,
+    fm_extent_count   : u32,
+    fm_reserved       : u32,
+    fm_extents        : [FiemapExtent; 1],
+}
+
+// Physical offset of the first extent of a file on its device. Empty files
+// and files stored inline in their inode have no extents, extents of data
+// not yet written have no known offset.
+pub fn first_physical_offset(path : &str) -> io::Result<Option<u64>> {
+    let file = File::open(path)?;
+    let mut request = Fiemap {
+        fm_length       : u64::MAX,
+        fm_extent_count : 1,
+        ..Default::default()
+    };
+
+    let result = unsafe {
+        libc::ioctl(
+            file.as_raw_fd(), FS_IOC_FIEMAP as _,
+            &mut request as *mut Fiemap
+        )
+    };
+
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let extent = &request.fm_extents[0];
+    let flags  = FIEMAP_EXTENT_UNKNOWN | FIEMAP_EXTENT_DELALLOC;
+
+    if (request.fm_mapped_extents == 0) || (extent.fe_flags & flags != 0) {
+        return Ok(None);
+    }
+
+    Ok(Some(extent.fe_physical))
+}
@@ -0,0 +1,51 @@
+use std::process;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use error::{Error, Result};
+
+pub const EXIT_INTERRUPTED : i32 = 130;
+
+// Shared flag that is polled between file operations. Cloned handles refer
+// to the same flag.
+#[derive(Clone)]
+#[derive(Debug)]
+#[derive(Default)]
+pub struct Interrupt {
+    flag : Arc<AtomicBool>,
+}
+
+impl Interrupt {
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set(&self) {
+        self.flag.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_set(&self) -> bool {
+        self.flag.load(Ordering::SeqCst)
+    }
+
+    // The first SIGINT/SIGTERM only raises the flag, the second one exits
+    // immediately.
+    pub fn install_handler(&self) -> Result<()> {
+        let flag = self.flag.clone();
+
+        ctrlc::set_handler(
+            move || {
+                if flag.swap(true, Ordering::SeqCst) {
+                    process::exit(EXIT_INTERRUPTED);
+                }
+
+                warn!("Interrupted. Finishing current operation...");
+            }
+        ).map_err(
+            |e| {
+                Error::Config(format!("Failed to set signal handler: {}", e))
+            }
+        )
+    }
+}
@@ -1,2 +1,7 @@
+pub mod error_log;
+pub mod fiemap;
+pub mod interrupt;
 pub mod path;
 pub mod progress;
+pub mod reader;
+pub mod xattr;
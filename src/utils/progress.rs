@@ -1,13 +1,47 @@
+use std::cell::RefCell;
+use std::io::Write;
+use std::path::Path;
+
 use indicatif::{ProgressBar, ProgressStyle};
 
-pub fn get_progress_bar(size : u64, title : &str, show_progress : bool)
-    -> Option<ProgressBar>
-{
+use error::Error;
 
-    if ! show_progress {
-        return None;
-    }
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub enum DedupOp {
+    Remove, Hardlink, Symlink, Metadata,
+}
+
+pub enum ProgressEvent<'a> {
+    ScanStart   { root : &'a str },
+    ScanEntry   { path : &'a Path },
+    ScanFinish  { root : &'a str },
+    StageStart  { name : &'a str, total : u64 },
+    StageTick   { name : &'a str, path : Option<&'a str> },
+    StageFinish { name : &'a str },
+    Error       { error : &'a Error },
+    Dedup       { op : DedupOp, src : &'a str, dst : &'a str, dry_run : bool },
+}
+
+pub trait ProgressSink {
+    fn event(&self, event : &ProgressEvent);
+}
 
+pub struct NullProgress;
+
+pub struct IndicatifProgress {
+    spinner : RefCell<Option<ProgressBar>>,
+    pbar    : RefCell<Option<ProgressBar>>,
+}
+
+pub struct JsonProgress<W : Write> {
+    out : RefCell<W>,
+}
+
+pub fn get_progress_bar(size : u64, title : &str) -> ProgressBar
+{
     let result = ProgressBar::new(size);
     result.set_style(
         ProgressStyle::default_bar()
@@ -20,6 +54,206 @@ pub fn get_progress_bar(size : u64, title : &str, show_progress : bool)
 
     result.set_message(title);
 
-    Some(result)
+    result
+}
+
+pub fn get_spinner(title : &str) -> ProgressBar
+{
+    let result = ProgressBar::new_spinner();
+    result.set_style(ProgressStyle::default_bar()
+        .template("[{elapsed}] {spinner} {prefix} {wide_msg}")
+    );
+    result.set_prefix(title);
+
+    result
+}
+
+impl ProgressSink for NullProgress {
+    fn event(&self, _event : &ProgressEvent) {}
+}
+
+impl Default for IndicatifProgress {
+    fn default() -> Self {
+        IndicatifProgress {
+            spinner : RefCell::new(None),
+            pbar    : RefCell::new(None),
+        }
+    }
+}
+
+impl IndicatifProgress {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl ProgressSink for IndicatifProgress {
+
+    fn event(&self, event : &ProgressEvent) {
+        match event {
+            ProgressEvent::ScanStart { .. } => {
+                *self.spinner.borrow_mut() = Some(get_spinner("Scanning"));
+            },
+            ProgressEvent::ScanEntry { path } => {
+                if let Some(s) = self.spinner.borrow().as_ref() {
+                    s.set_message(&path.to_string_lossy());
+                }
+            },
+            ProgressEvent::ScanFinish { .. } => {
+                if let Some(s) = self.spinner.borrow_mut().take() {
+                    s.finish_with_message("Done");
+                }
+            },
+            ProgressEvent::StageStart { name, total } => {
+                *self.pbar.borrow_mut() = Some(get_progress_bar(*total, name));
+            },
+            ProgressEvent::StageTick { name, path } => {
+                if let Some(pb) = self.pbar.borrow().as_ref() {
+                    if let Some(path) = path {
+                        pb.set_message(&format!("{}: {}", name, path));
+                    }
+                    pb.inc(1);
+                }
+            },
+            ProgressEvent::StageFinish { .. } => {
+                if let Some(pb) = self.pbar.borrow_mut().take() {
+                    pb.abandon();
+                }
+            },
+            _ => {},
+        }
+    }
+}
+
+pub fn json_escape(s : &str) -> String
+{
+    let mut result = String::with_capacity(s.len() + 2);
+    result.push('"');
+
+    for c in s.chars() {
+        match c {
+            '"'  => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\r' => result.push_str("\\r"),
+            '\t' => result.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                result.push_str(&format!("\\u{:04x}", c as u32))
+            },
+            c => result.push(c),
+        }
+    }
+
+    result.push('"');
+    result
+}
+
+fn format_json_event(event : &ProgressEvent) -> String
+{
+    match event {
+        ProgressEvent::ScanStart { root } => format!(
+            "{{\"event\":\"scan_start\",\"root\":{}}}", json_escape(root)
+        ),
+        ProgressEvent::ScanEntry { path } => format!(
+            "{{\"event\":\"scan_entry\",\"path\":{}}}",
+            json_escape(&path.to_string_lossy())
+        ),
+        ProgressEvent::ScanFinish { root } => format!(
+            "{{\"event\":\"scan_finish\",\"root\":{}}}", json_escape(root)
+        ),
+        ProgressEvent::StageStart { name, total } => format!(
+            "{{\"event\":\"stage_start\",\"name\":{},\"total\":{}}}",
+            json_escape(name), total
+        ),
+        ProgressEvent::StageTick { name, path } => format!(
+            "{{\"event\":\"stage_tick\",\"name\":{},\"path\":{}}}",
+            json_escape(name), path.map_or("null".to_string(), json_escape)
+        ),
+        ProgressEvent::StageFinish { name } => format!(
+            "{{\"event\":\"stage_finish\",\"name\":{}}}", json_escape(name)
+        ),
+        ProgressEvent::Error { error } => format!(
+            "{{\"event\":\"error\",\"stage\":{},\"path\":{},\
+             \"message\":{}}}",
+            json_escape(error.stage()),
+            error.path().map_or("null".to_string(), json_escape),
+            json_escape(&error.to_string())
+        ),
+        ProgressEvent::Dedup { op, src, dst, dry_run } => format!(
+            "{{\"event\":\"dedup\",\"op\":{},\"src\":{},\"dst\":{},\
+             \"dry_run\":{}}}",
+            json_escape(&format!("{:?}", op).to_lowercase()),
+            json_escape(src), json_escape(dst), dry_run
+        ),
+    }
+}
+
+impl<W : Write> JsonProgress<W> {
+    pub fn new(out : W) -> Self {
+        JsonProgress { out : RefCell::new(out) }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out.into_inner()
+    }
 }
 
+impl<W : Write> ProgressSink for JsonProgress<W> {
+
+    fn event(&self, event : &ProgressEvent) {
+        let mut out = self.out.borrow_mut();
+
+        if let Err(e) = writeln!(out, "{}", format_json_event(event)) {
+            warn!("Failed to write progress event: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_escape() {
+        assert_eq!(json_escape("a"), "\"a\"");
+        assert_eq!(json_escape("a\"b\\c"), "\"a\\\"b\\\\c\"");
+        assert_eq!(json_escape("a\nb\u{1}"), "\"a\\nb\\u0001\"");
+    }
+
+    #[test]
+    fn test_json_progress() {
+        let sink = JsonProgress::new(Vec::new());
+
+        sink.event(&ProgressEvent::StageStart { name : "hash", total : 2 });
+        sink.event(&ProgressEvent::StageTick {
+            name : "hash", path : Some("/a")
+        });
+        sink.event(&ProgressEvent::StageTick { name : "dedup", path : None });
+        sink.event(&ProgressEvent::Dedup {
+            op : DedupOp::Hardlink, src : "/a", dst : "/b\"", dry_run : false
+        });
+
+        assert_eq!(
+            String::from_utf8(sink.into_inner()).unwrap(),
+            "{\"event\":\"stage_start\",\"name\":\"hash\",\"total\":2}\n\
+             {\"event\":\"stage_tick\",\"name\":\"hash\",\"path\":\"/a\"}\n\
+             {\"event\":\"stage_tick\",\"name\":\"dedup\",\"path\":null}\n\
+             {\"event\":\"dedup\",\"op\":\"hardlink\",\"src\":\"/a\",\
+             \"dst\":\"/b\\\"\",\"dry_run\":false}\n"
+        );
+    }
+
+    #[test]
+    fn test_json_progress_error() {
+        let sink  = JsonProgress::new(Vec::new());
+        let error = Error::Config("bad".to_string());
+
+        sink.event(&ProgressEvent::Error { error : &error });
+
+        assert_eq!(
+            String::from_utf8(sink.into_inner()).unwrap(),
+            "{\"event\":\"error\",\"stage\":\"config\",\"path\":null,\
+             \"message\":\"bad\"}\n"
+        );
+    }
+}
@@ -0,0 +1,208 @@
+use std::cell::RefCell;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::rc::Rc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use dups::hasher::HashAlgorithm;
+
+const IOPRIO_WHO_PROCESS : libc::c_int = 1;
+const IOPRIO_CLASS_IDLE  : libc::c_int = 3;
+const IOPRIO_CLASS_SHIFT : libc::c_int = 13;
+
+// How heuristics and comparisons open files. With hints, reads leave atime
+// alone and their pages are dropped from the page cache once a file is
+// closed, so that a scan does not evict the working set of other programs.
+// Files opened by clones of a reader share its rate limit. Files smaller
+// than `small_size` are read whole once, their `digest` serves all stages.
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct Reader {
+    pub hints      : bool,
+    pub order      : ReadOrder,
+    pub throttle   : Option<Rc<RefCell<Throttle>>>,
+    pub small_size : u64,
+    pub digest     : HashAlgorithm,
+}
+
+// Order in which a stage reads files. Inode numbers approximate the disk
+// layout, physical offsets of the first extent (FIEMAP) follow it on
+// filesystems that support them.
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub enum ReadOrder {
+    Inode, Physical,
+}
+
+// Token bucket of bytes. Up to one second of reads may burst after an idle
+// period, longer reads are slowed down to `rate` bytes per second.
+#[derive(Debug)]
+pub struct Throttle {
+    rate      : f64,
+    allowance : f64,
+    last      : Instant,
+}
+
+// A file opened by `Reader`. Cached pages are dropped when it is closed.
+pub struct ReadFile {
+    file     : File,
+    hints    : bool,
+    throttle : Option<Rc<RefCell<Throttle>>>,
+}
+
+fn fadvise(file : &File, advice : libc::c_int) {
+    // Only a hint, failures are harmless
+    unsafe {
+        libc::posix_fadvise(file.as_raw_fd(), 0, 0, advice);
+    }
+}
+
+// O_NOATIME is only permitted for the owner of a file (or root)
+fn open_noatime(path : &str) -> io::Result<File> {
+    let result = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOATIME)
+        .open(path);
+
+    match result {
+        Err(e) if e.raw_os_error() == Some(libc::EPERM) => File::open(path),
+        other => other,
+    }
+}
+
+// Puts the process into the idle I/O scheduling class, so that its reads are
+// only served when no other process uses the disk
+pub fn set_idle_io_priority() -> io::Result<()> {
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0,
+            IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT
+        )
+    };
+
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+impl Throttle {
+
+    pub fn new(rate : u64) -> Self {
+        let rate = rate.max(1) as f64;
+        Throttle { rate, allowance : rate, last : Instant::now() }
+    }
+
+    pub fn consume(&mut self, n_bytes : usize) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+
+        self.last      = now;
+        self.allowance = (self.allowance + elapsed * self.rate).min(self.rate);
+        self.allowance -= n_bytes as f64;
+
+        if self.allowance < 0.0 {
+            thread::sleep(Duration::from_secs_f64(-self.allowance / self.rate));
+        }
+    }
+}
+
+impl Default for Reader {
+    fn default() -> Self {
+        Reader {
+            hints      : true,
+            order      : ReadOrder::Inode,
+            throttle   : None,
+            small_size : 16 * 1024,
+            digest     : HashAlgorithm::Sha512,
+        }
+    }
+}
+
+impl Reader {
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn hints(mut self, value : bool) -> Self {
+        self.hints = value;
+        self
+    }
+
+    pub fn order(mut self, order : ReadOrder) -> Self {
+        self.order = order;
+        self
+    }
+
+    // 0 disables reading small files at once
+    pub fn small_size(mut self, size : u64) -> Self {
+        self.small_size = size;
+        self
+    }
+
+    // Digests of small files are never verified, the hash must be exact
+    pub fn digest(mut self, algo : HashAlgorithm) -> Self {
+        self.digest = algo;
+        self
+    }
+
+    pub fn is_small(&self, size : u64) -> bool {
+        size < self.small_size
+    }
+
+    // Bytes per second read by all files of this reader, 0 is unlimited
+    pub fn max_rate(mut self, rate : Option<u64>) -> Self {
+        self.throttle = rate.filter( |x| { *x > 0 } ).map(
+            |x| { Rc::new(RefCell::new(Throttle::new(x))) }
+        );
+        self
+    }
+
+    pub fn open(&self, path : &str) -> io::Result<ReadFile> {
+        let file = if self.hints {
+            let file = open_noatime(path)?;
+            fadvise(&file, libc::POSIX_FADV_SEQUENTIAL);
+            file
+        }
+        else {
+            File::open(path)?
+        };
+
+        Ok(ReadFile {
+            file, hints : self.hints, throttle : self.throttle.clone()
+        })
+    }
+}
+
+impl Read for ReadFile {
+    fn read(&mut self, buf : &mut [u8]) -> io::Result<usize> {
+        let result = self.file.read(buf)?;
+
+        if let Some(throttle) = &self.throttle {
+            throttle.borrow_mut().consume(result);
+        }
+
+        Ok(result)
+    }
+}
+
+impl Seek for ReadFile {
+    fn seek(&mut self, pos : SeekFrom) -> io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
+
+impl Drop for ReadFile {
+    fn drop(&mut self) {
+        if self.hints {
+            fadvise(&self.file, libc::POSIX_FADV_DONTNEED);
+        }
+    }
+}
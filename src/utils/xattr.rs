@@ -0,0 +1,95 @@
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+pub type XAttrs = Vec<(Vec<u8>, Vec<u8>)>;
+
+fn to_cstring(bytes : &[u8]) -> io::Result<CString> {
+    CString::new(bytes).map_err(
+        |e| { io::Error::new(io::ErrorKind::InvalidInput, e) }
+    )
+}
+
+fn is_unsupported(e : &io::Error) -> bool {
+    e.raw_os_error() == Some(libc::ENOTSUP)
+}
+
+// Both calls follow the list/get protocol: query the size first, then fill
+// a buffer of that size
+fn list_names(path : &CString) -> io::Result<Vec<u8>> {
+    let size = unsafe {
+        libc::llistxattr(path.as_ptr(), std::ptr::null_mut(), 0)
+    };
+
+    if size < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut buf : Vec<u8> = vec![ 0; size as usize ];
+    let size = unsafe {
+        libc::llistxattr(
+            path.as_ptr(), buf.as_mut_ptr() as *mut libc::c_char, buf.len()
+        )
+    };
+
+    if size < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    buf.truncate(size as usize);
+    Ok(buf)
+}
+
+fn get_value(path : &CString, name : &CString) -> io::Result<Vec<u8>> {
+    let size = unsafe {
+        libc::lgetxattr(
+            path.as_ptr(), name.as_ptr(), std::ptr::null_mut(), 0
+        )
+    };
+
+    if size < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut buf : Vec<u8> = vec![ 0; size as usize ];
+    let size = unsafe {
+        libc::lgetxattr(
+            path.as_ptr(), name.as_ptr(),
+            buf.as_mut_ptr() as *mut libc::c_void, buf.len()
+        )
+    };
+
+    if size < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    buf.truncate(size as usize);
+    Ok(buf)
+}
+
+// Returns xattrs sorted by name. Filesystems without xattr support have none.
+pub fn read_xattrs(path : &Path) -> io::Result<XAttrs> {
+    let c_path = to_cstring(path.as_os_str().as_bytes())?;
+
+    let names = match list_names(&c_path) {
+        Ok(x)                         => x,
+        Err(e) if is_unsupported(&e) => return Ok(Vec::new()),
+        Err(e)                        => return Err(e),
+    };
+
+    let mut result : XAttrs = Vec::new();
+
+    for name in names.split( |x| { *x == 0 } ).filter( |x| { ! x.is_empty() } )
+    {
+        // Attributes removed after listing are skipped
+        match get_value(&c_path, &to_cstring(name)?) {
+            Ok(value) => result.push((name.to_vec(), value)),
+            Err(e) if e.raw_os_error() == Some(libc::ENODATA) => {},
+            Err(e) => return Err(e),
+        }
+    }
+
+    result.sort();
+    Ok(result)
+}
@@ -1,18 +1,26 @@
 extern crate fdedup;
 #[macro_use] mod utils;
 
-use std::fs;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
 use std::os::unix::prelude::*;
+use std::process::Command;
 
 use fdedup::args::DedupAction;
 use fdedup::dups::dedup::deduplicate;
+use fdedup::dups::hasher::HashAlgorithm;
+use fdedup::dups::script::write_script;
+use fdedup::utils::interrupt::Interrupt;
+use fdedup::utils::progress::NullProgress;
 use utils::{
     create_basic_fs_structure, hardlink_files, copy_files, find_duplicates,
+    find_duplicates_with_references
 };
 use utils::fs_skel::*;
 
 const N_READ : usize = 200;
-const ALGO   : crypto_hash::Algorithm = crypto_hash::Algorithm::SHA512;
+const ALGO   : HashAlgorithm = HashAlgorithm::Sha512;
 
 #[macro_export]
 macro_rules! test_dedup {
@@ -55,7 +63,8 @@ fn test_dedup_hardlinks() {
     ).unwrap();
 
     deduplicate(
-        &duplicates, DedupAction::Hardlink, true, false, false
+        &duplicates, DedupAction::Hardlink, true, false, &NullProgress,
+        &Interrupt::new(), None, &mut io::sink()
     ).unwrap();
 
     test_dedup!(dir, FILES, COPIES, false);
@@ -74,7 +83,8 @@ fn test_dedup_symlinks() {
     ).unwrap();
 
     deduplicate(
-        &duplicates, DedupAction::Symlink, true, false, false
+        &duplicates, DedupAction::Symlink, true, false, &NullProgress,
+        &Interrupt::new(), None, &mut io::sink()
     ).unwrap();
 
     test_dedup!(dir, FILES, COPIES, true);
@@ -82,3 +92,208 @@ fn test_dedup_symlinks() {
     dir.close().unwrap();
 }
 
+
+#[test]
+fn test_dedup_dry_run_plan() {
+    let dir = create_basic_fs_structure(&DIRS, &FILES).unwrap();
+    copy_files(&dir, &FILES, &COPIES).unwrap();
+
+    let duplicates = find_duplicates(
+        &[dir.path().to_str().unwrap()], false, N_READ, ALGO
+    ).unwrap();
+
+    let mut plan : Vec<u8> = Vec::new();
+
+    deduplicate(
+        &duplicates, DedupAction::Hardlink, true, true, &NullProgress,
+        &Interrupt::new(), None, &mut plan
+    ).unwrap();
+
+    let plan     = String::from_utf8(plan).unwrap();
+    let n_copies : usize = COPIES.iter().map( |x| x.len() ).sum();
+
+    assert!(plan.starts_with("Dry run:\n[0]\n"));
+    assert_eq!(plan.matches("\n  rm ").count(), n_copies, "{}", plan);
+
+    for copy in COPIES.iter().flat_map( |x| x.iter() ) {
+        let meta = fs::metadata(dir.path().join(copy)).unwrap();
+        assert_eq!(meta.nlink(), 1, "Dry run linked '{}'", copy);
+    }
+
+    dir.close().unwrap();
+}
+
+
+fn run_script(dir : &tempfile::TempDir, action : DedupAction) -> bool {
+    let duplicates = find_duplicates(
+        &[dir.path().to_str().unwrap()], false, N_READ, ALGO
+    ).unwrap();
+
+    let script = dir.path().join("dedup.sh");
+    write_script(&mut File::create(&script).unwrap(), &duplicates, action)
+        .unwrap();
+
+    Command::new("sh").arg(&script).status().unwrap().success()
+}
+
+#[test]
+fn test_dedup_script_symlinks() {
+    let dir = create_basic_fs_structure(&DIRS, &FILES).unwrap();
+    copy_files    (&dir, &FILES, &COPIES).unwrap();
+    hardlink_files(&dir, &FILES, &LINKS).unwrap();
+
+    assert!(run_script(&dir, DedupAction::Symlink));
+    test_dedup!(dir, FILES, COPIES, true);
+
+    dir.close().unwrap();
+}
+
+#[test]
+fn test_dedup_script_skips_changed_files() {
+    let dir = create_basic_fs_structure(&DIRS, &FILES).unwrap();
+    copy_files(&dir, &FILES, &COPIES).unwrap();
+
+    let duplicates = find_duplicates(
+        &[dir.path().to_str().unwrap()], false, N_READ, ALGO
+    ).unwrap();
+
+    let script = dir.path().join("dedup.sh");
+    write_script(
+        &mut File::create(&script).unwrap(), &duplicates,
+        DedupAction::Hardlink
+    ).unwrap();
+
+    let changed = dir.path().join(COPIES[0][0]);
+    let inode   = fs::metadata(&changed).unwrap().ino();
+
+    OpenOptions::new().append(true).open(&changed).unwrap()
+        .write_all(b"changed").unwrap();
+
+    assert!(! Command::new("sh").arg(&script).status().unwrap().success());
+    assert_eq!(fs::metadata(&changed).unwrap().ino(), inode);
+
+    dir.close().unwrap();
+}
+
+#[test]
+fn test_dedup_script_keeps_existing_temp_files() {
+    let dir = create_basic_fs_structure(&DIRS, &FILES).unwrap();
+    copy_files(&dir, &FILES, &COPIES).unwrap();
+
+    let files = FILES.iter().map( |x| x.0 )
+        .chain(COPIES.iter().flat_map( |x| x.iter().cloned() ));
+
+    let mut inodes : Vec<(String, u64)> = Vec::new();
+
+    for file in files {
+        let path = dir.path().join(file);
+        fs::write(format!("{}.fdedup-tmp", path.display()), file).unwrap();
+        inodes.push((file.to_string(), fs::metadata(&path).unwrap().ino()));
+    }
+
+    assert!(! run_script(&dir, DedupAction::Hardlink));
+
+    for (file, inode) in inodes.iter() {
+        let path = dir.path().join(file);
+        let tmp  = format!("{}.fdedup-tmp", path.display());
+
+        assert_eq!(fs::metadata(&path).unwrap().ino(), *inode);
+        assert_eq!(fs::read_to_string(&tmp).unwrap(), *file);
+    }
+
+    dir.close().unwrap();
+}
+
+#[test]
+fn test_dedup_interrupted() {
+    let dir = create_basic_fs_structure(&DIRS, &FILES).unwrap();
+    copy_files(&dir, &FILES, &COPIES).unwrap();
+
+    let duplicates = find_duplicates(
+        &[dir.path().to_str().unwrap()], false, N_READ, ALGO
+    ).unwrap();
+
+    let interrupt = Interrupt::new();
+    interrupt.set();
+
+    let summary = deduplicate(
+        &duplicates, DedupAction::Hardlink, true, false, &NullProgress,
+        &interrupt, None, &mut io::sink()
+    ).unwrap();
+
+    assert!(summary.interrupted);
+    assert_eq!(summary.n_groups,     duplicates.len());
+    assert_eq!(summary.n_done_files, 0);
+    assert!(summary.done_groups.is_empty());
+
+    for ((file, _size), copies) in FILES.iter().zip(COPIES.iter()) {
+        let inode = fs::metadata(dir.path().join(file)).unwrap().ino();
+
+        for copy in copies.iter() {
+            assert_ne!(
+                fs::metadata(dir.path().join(copy)).unwrap().ino(), inode,
+                "Copy '{}' was linked after interruption", copy
+            );
+        }
+    }
+
+    dir.close().unwrap();
+}
+
+#[test]
+fn test_dedup_hardlinks_with_reference() {
+    let dir = create_basic_fs_structure(&DIRS, &FILES).unwrap();
+    copy_files    (&dir, &FILES, &COPIES).unwrap();
+    hardlink_files(&dir, &FILES, &LINKS).unwrap();
+
+    let reference = dir.path().join("dir3");
+
+    let duplicates = find_duplicates_with_references(
+        &[dir.path().to_str().unwrap()], &[reference.to_str().unwrap()],
+        false, N_READ, ALGO
+    ).unwrap();
+
+    let mut protected_inodes : HashMap<String, u64> = HashMap::new();
+
+    for entry in duplicates.iter().flatten() {
+        for path in entry.paths.iter().filter( |x| x.protected ) {
+            assert!(path.path.starts_with(reference.to_str().unwrap()));
+            protected_inodes.insert(path.path.clone(), entry.inode);
+        }
+    }
+
+    assert!(! protected_inodes.is_empty());
+
+    deduplicate(
+        &duplicates, DedupAction::Hardlink, true, false, &NullProgress,
+        &Interrupt::new(), None, &mut io::sink()
+    ).unwrap();
+
+    for (path, inode) in protected_inodes.iter() {
+        assert_eq!(
+            fs::metadata(path).unwrap().ino(), *inode,
+            "Protected file '{}' has been modified", path
+        );
+    }
+
+    for group in duplicates.iter() {
+        let group_inodes : Vec<u64> = group.iter()
+            .filter(|x| { x.is_protected() })
+            .map(   |x| { x.inode })
+            .collect();
+
+        if group_inodes.is_empty() {
+            continue;
+        }
+
+        for path in group.iter().flat_map( |x| x.paths.iter() ) {
+            let inode = fs::metadata(&path.path).unwrap().ino();
+            assert!(
+                group_inodes.contains(&inode),
+                "File '{}' does not point to a protected file", path.path
+            );
+        }
+    }
+
+    dir.close().unwrap();
+}
@@ -0,0 +1,892 @@
+extern crate fdedup;
+#[macro_use] mod utils;
+
+use std::cell::RefCell;
+use std::fs::{self, FileTimes};
+use std::io::{self, Read};
+use std::os::unix::prelude::*;
+use std::path::Path;
+use std::rc::Rc;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use fdedup::{Deduplicator, Error, ScanConfig};
+use fdedup::args::DedupAction;
+use fdedup::dups::dedup::find_group_leader_index;
+use fdedup::dups::filter::RootSpan;
+use fdedup::dups::hasher::HashAlgorithm;
+use fdedup::dups::heuristics::{Attribute, Heuristic, Stage};
+use fdedup::fs_entry::{FSEntry, HKey};
+use fdedup::dups::journal::undo_journal;
+use fdedup::dups::metadata::MetadataPolicy;
+use fdedup::utils::interrupt::Interrupt;
+use fdedup::utils::reader::{ReadOrder, Reader};
+use fdedup::utils::progress::{
+    DedupOp, NullProgress, ProgressEvent, ProgressSink
+};
+use utils::{create_basic_fs_structure, hardlink_files, copy_files};
+use utils::fs_skel::*;
+
+struct EventLog {
+    events : Rc<RefCell<Vec<String>>>,
+}
+
+impl ProgressSink for EventLog {
+    fn event(&self, event : &ProgressEvent) {
+        let name = match event {
+            ProgressEvent::StageStart { name, .. } => format!("start {}", name),
+            ProgressEvent::Dedup { op : DedupOp::Remove, .. } => {
+                "remove".to_string()
+            },
+            ProgressEvent::Dedup { op : DedupOp::Hardlink, .. } => {
+                "hardlink".to_string()
+            },
+            _ => return,
+        };
+
+        self.events.borrow_mut().push(name);
+    }
+}
+
+#[test]
+fn test_deduplicator_search() {
+    let dir = create_basic_fs_structure(&DIRS, &FILES).unwrap();
+    copy_files    (&dir, &FILES, &COPIES).unwrap();
+    hardlink_files(&dir, &FILES, &LINKS).unwrap();
+
+    let report = Deduplicator::new(
+        ScanConfig::new().root(dir.path().to_str().unwrap())
+    ).search().unwrap();
+
+    let n_copies : usize = COPIES.iter().map( |x| x.len() ).sum();
+    let n_links  : usize = LINKS .iter().map( |x| x.len() ).sum();
+
+    assert_eq!(report.groups.len(),        FILES.len());
+    assert_eq!(report.stats.n_groups,      FILES.len());
+    assert_eq!(report.stats.n_inodes,      FILES.len() + n_copies);
+    assert_eq!(report.stats.n_files,       FILES.len() + n_copies + n_links);
+    assert_eq!(report.stats.n_dupl_inodes, n_copies);
+    assert_eq!(
+        report.stats.saved_size,
+        FILES.iter().zip(COPIES.iter())
+            .map( |((_, size), copies)| { size * (copies.len() as u64) } )
+            .sum::<u64>()
+    );
+
+    dir.close().unwrap();
+}
+
+#[test]
+fn test_deduplicator_physical_read_order() {
+    let dir = create_basic_fs_structure(&DIRS, &FILES).unwrap();
+    copy_files    (&dir, &FILES, &COPIES).unwrap();
+    hardlink_files(&dir, &FILES, &LINKS).unwrap();
+
+    let search = | order | {
+        Deduplicator::new(ScanConfig::new().root(dir.path().to_str().unwrap()))
+            .read_order(order)
+            .search().unwrap()
+    };
+
+    // Only the order of reads changes
+    assert_eq!(
+        search(ReadOrder::Physical).stats, search(ReadOrder::Inode).stats
+    );
+
+    // Offsets are queried once and kept for later stages
+    for entry in search(ReadOrder::Physical).groups.iter().flatten() {
+        assert!(entry.offset.is_some());
+    }
+
+    for entry in search(ReadOrder::Inode).groups.iter().flatten() {
+        assert!(entry.offset.is_none());
+    }
+
+    dir.close().unwrap();
+}
+
+#[test]
+fn test_deduplicator_stages() {
+    let dir = create_basic_fs_structure(&DIRS, &FILES).unwrap();
+    copy_files(&dir, &FILES, &COPIES).unwrap();
+
+    let deduplicator = || {
+        Deduplicator::new(ScanConfig::new().root(dir.path().to_str().unwrap()))
+    };
+
+    // Order of groups of equal size and of entries within a group depends
+    // on keys of the last stage
+    let paths = | groups : &[Vec<FSEntry>] | -> Vec<Vec<String>> {
+        let mut result : Vec<Vec<String>> = groups.iter().map(
+            |x| {
+                let mut group : Vec<String> =
+                    x.iter().map( |y| y.paths[0].path.clone() ).collect();
+
+                group.sort();
+                group
+            }
+        ).collect();
+
+        result.sort();
+        result
+    };
+
+    let expected = paths(&deduplicator().search().unwrap().groups);
+
+    for stages in [
+        &[ Stage::Hash ][..],
+        &[ Stage::MiddleBytes, Stage::FirstBytes, Stage::Hash ][..],
+        &[ Stage::BlockHash ][..],
+        &[ Stage::FirstBytes, Stage::BlockHash ][..],
+        &[ Stage::Compare ][..],
+        &[ Stage::LastBytes, Stage::Compare ][..],
+    ].iter() {
+        let report = deduplicator().stages(stages).search().unwrap();
+        assert_eq!(paths(&report.groups), expected);
+    }
+
+    // Small files are hashed whole instead of compared
+    let events = Rc::new(RefCell::new(Vec::new()));
+    let report = deduplicator().small_size(0).stages(&[ Stage::Compare ])
+        .progress(Box::new(EventLog { events : events.clone() }))
+        .search().unwrap();
+
+    assert_eq!(paths(&report.groups), expected);
+    assert!(events.borrow().contains(&"start Comparing files".to_string()));
+
+    // Byte stages after the hash would replace its grouping
+    assert!(matches!(
+        deduplicator().stages(&[ Stage::Hash, Stage::LastBytes ]).search(),
+        Err(Error::Config(_))
+    ));
+
+    dir.close().unwrap();
+}
+
+#[test]
+fn test_deduplicator_block_hash() {
+    let dir = tempfile::tempdir().unwrap();
+
+    // Files share the first blocks and differ only in the last one
+    let mut data : Vec<u8> = (0..(1 << 20)).map( |x| { x as u8 } ).collect();
+    fs::write(dir.path().join("a"), &data).unwrap();
+    fs::write(dir.path().join("b"), &data).unwrap();
+
+    *data.last_mut().unwrap() ^= 1;
+    fs::write(dir.path().join("c"), &data).unwrap();
+
+    let report = Deduplicator::new(
+        ScanConfig::new().root(dir.path().to_str().unwrap())
+    )
+        .stages(&[ Stage::BlockHash ])
+        .search().unwrap();
+
+    let paths : Vec<Vec<String>> = report.groups.iter()
+        .map( |x| { x.iter().map( |y| y.paths[0].path.clone() ).collect() } )
+        .collect();
+
+    assert_eq!(paths.len(), 1);
+
+    let mut found = paths[0].clone();
+    found.sort();
+
+    assert_eq!(found, vec![
+        dir.path().join("a").to_str().unwrap().to_string(),
+        dir.path().join("b").to_str().unwrap().to_string(),
+    ]);
+
+    dir.close().unwrap();
+}
+
+#[test]
+fn test_deduplicator_fast_hashes() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let mut data : Vec<u8> = (0..(1 << 16)).map( |x| { x as u8 } ).collect();
+    fs::write(dir.path().join("a"), &data).unwrap();
+    fs::write(dir.path().join("b"), &data).unwrap();
+
+    *data.last_mut().unwrap() ^= 1;
+    fs::write(dir.path().join("c"), &data).unwrap();
+
+    // Groups of xxh3 are verified, even those too large to compare directly
+    for algo in [ HashAlgorithm::Blake3, HashAlgorithm::Xxh3 ].iter() {
+        for stages in [ vec![ Stage::Hash ], vec![ Stage::BlockHash ] ] {
+            let report = Deduplicator::new(
+                ScanConfig::new().root(dir.path().to_str().unwrap())
+            )
+                .hash(*algo)
+                .compare_max(0)
+                .stages(&stages)
+                .search().unwrap();
+
+            assert_eq!(report.groups.len(), 1);
+
+            let mut found : Vec<String> = report.groups[0].iter()
+                .map( |x| { x.paths[0].path.clone() } )
+                .collect();
+            found.sort();
+
+            assert_eq!(found, vec![
+                dir.path().join("a").to_str().unwrap().to_string(),
+                dir.path().join("b").to_str().unwrap().to_string(),
+            ]);
+        }
+    }
+
+    dir.close().unwrap();
+}
+
+#[test]
+fn test_deduplicator_max_read_rate() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let data : Vec<u8> = (0..(64 * 1024)).map( |x| { x as u8 } ).collect();
+    fs::write(dir.path().join("a"), &data).unwrap();
+    fs::write(dir.path().join("b"), &data).unwrap();
+
+    // Hashing reads 128K, the first 64K are allowed at once
+    let start  = Instant::now();
+    let report = Deduplicator::new(
+        ScanConfig::new().root(dir.path().to_str().unwrap())
+    )
+        .compare_max(0)
+        .max_read_rate(Some(64 * 1024))
+        .search().unwrap();
+
+    assert_eq!(report.groups.len(), 1);
+    assert!(start.elapsed() >= Duration::from_millis(900));
+
+    // A rate of 0 does not limit reads
+    let start  = Instant::now();
+    let report = Deduplicator::new(
+        ScanConfig::new().root(dir.path().to_str().unwrap())
+    )
+        .compare_max(0)
+        .max_read_rate(Some(0))
+        .search().unwrap();
+
+    assert_eq!(report.groups.len(), 1);
+    assert!(start.elapsed() < Duration::from_millis(900));
+
+    dir.close().unwrap();
+}
+
+// Files are only duplicates if they also have the same name
+struct FileName;
+
+impl Heuristic for FileName {
+
+    fn name(&self) -> String {
+        "file name".to_string()
+    }
+
+    fn cost(&self, _size : u64) -> u64 {
+        0
+    }
+
+    fn depends_on_content(&self) -> bool {
+        false
+    }
+
+    fn key(&self, entry : &FSEntry, _reader : &Reader)
+        -> std::io::Result<HKey>
+    {
+        let path = Path::new(&entry.paths[0].path);
+        Ok(HKey(path.file_name().unwrap().as_bytes().to_vec()))
+    }
+}
+
+#[test]
+fn test_deduplicator_custom_stage() {
+    let dir = create_basic_fs_structure(&DIRS, &FILES).unwrap();
+    copy_files(&dir, &FILES, &COPIES).unwrap();
+
+    let deduplicator = || {
+        Deduplicator::new(ScanConfig::new().root(dir.path().to_str().unwrap()))
+    };
+
+    let report = deduplicator()
+        .stages(&[ Stage::Custom(Rc::new(FileName)), Stage::Hash ])
+        .search().unwrap();
+
+    assert!(! report.groups.is_empty());
+
+    for group in report.groups.iter() {
+        let name = Path::new(&group[0].paths[0].path).file_name();
+
+        for entry in group.iter() {
+            assert_eq!(Path::new(&entry.paths[0].path).file_name(), name);
+        }
+    }
+
+    // Equal names do not mean equal contents
+    assert!(matches!(
+        deduplicator().stages(&[ Stage::Custom(Rc::new(FileName)) ]).search(),
+        Err(Error::Config(_))
+    ));
+
+    dir.close().unwrap();
+}
+
+// Files are only duplicates if their contents are equal ignoring case
+struct IgnoreCase;
+
+impl Heuristic for IgnoreCase {
+
+    fn name(&self) -> String {
+        "contents ignoring case".to_string()
+    }
+
+    fn cost(&self, size : u64) -> u64 {
+        size
+    }
+
+    fn is_exact(&self) -> bool {
+        true
+    }
+
+    fn key(&self, entry : &FSEntry, reader : &Reader)
+        -> std::io::Result<HKey>
+    {
+        let mut result : Vec<u8> = Vec::new();
+        reader.open(&entry.paths[0].path)?.read_to_end(&mut result)?;
+
+        Ok(HKey(result.to_ascii_lowercase()))
+    }
+}
+
+#[test]
+fn test_deduplicator_custom_content_stage() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("a"), "Some Text").unwrap();
+    fs::write(dir.path().join("b"), "some text").unwrap();
+
+    // Keys of small files are not replaced by the digest of their contents
+    let report = Deduplicator::new(
+        ScanConfig::new().root(dir.path().to_str().unwrap())
+    )
+        .stages(&[ Stage::Custom(Rc::new(IgnoreCase)) ])
+        .search().unwrap();
+
+    assert_eq!(report.groups.len(), 1);
+    assert_eq!(report.groups[0].len(), 2);
+
+    dir.close().unwrap();
+}
+
+#[test]
+fn test_deduplicator_filter() {
+    let dir = create_basic_fs_structure(&DIRS, &FILES).unwrap();
+    copy_files(&dir, &FILES, &COPIES).unwrap();
+
+    let report = Deduplicator::new(
+        ScanConfig::new()
+            .root(dir.path().join("dir1").to_str().unwrap())
+            .root(dir.path().join("dir3").to_str().unwrap())
+    )
+        .root_span(RootSpan::CrossRoot)
+        .search().unwrap();
+
+    let sizes : Vec<u64> = report.groups.iter().map( |x| x[0].size ).collect();
+
+    assert_eq!(
+        sizes, vec![ FILES[0].1, FILES[3].1 ],
+        "Unexpected cross-root duplicate groups: {:?}", report.groups
+    );
+
+    dir.close().unwrap();
+}
+
+#[test]
+fn test_deduplicator_run_hardlinks() {
+    let dir = create_basic_fs_structure(&DIRS, &FILES).unwrap();
+    copy_files(&dir, &FILES, &COPIES).unwrap();
+
+    let report = Deduplicator::new(
+        ScanConfig::new().root(dir.path().to_str().unwrap())
+    )
+        .action(DedupAction::Hardlink)
+        .run().unwrap();
+
+    assert_eq!(report.groups.len(), FILES.len());
+
+    for ((file, _size), copies) in FILES.iter().zip(COPIES.iter()) {
+        let inode = fs::metadata(dir.path().join(file)).unwrap().ino();
+
+        for copy in copies.iter() {
+            assert_eq!(
+                fs::metadata(dir.path().join(copy)).unwrap().ino(), inode,
+                "Copy '{}' is not linked to '{}'", copy, file
+            );
+        }
+    }
+
+    dir.close().unwrap();
+}
+
+#[test]
+fn test_deduplicator_journal_undo() {
+    let dir = create_basic_fs_structure(&DIRS, &FILES).unwrap();
+    copy_files(&dir, &FILES, &COPIES).unwrap();
+
+    let mtime = UNIX_EPOCH + Duration::new(1_000_000_000, 123);
+
+    for copy in COPIES.iter().flat_map( |x| x.iter() ) {
+        let path = dir.path().join(copy);
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+        fs::File::options().write(true).open(&path).unwrap()
+            .set_times(FileTimes::new().set_modified(mtime)).unwrap();
+    }
+
+    let journal = dir.path().join("journal");
+
+    Deduplicator::new(ScanConfig::new().root(dir.path().to_str().unwrap()))
+        .action(DedupAction::Hardlink)
+        .journal(journal.to_str().unwrap())
+        .run().unwrap();
+
+    let summary = undo_journal(
+        journal.to_str().unwrap(), true, false, &NullProgress,
+        &mut io::sink()
+    ).unwrap();
+
+    let n_copies : usize = COPIES.iter().map( |x| x.len() ).sum();
+
+    assert_eq!(summary.n_records,  n_copies);
+    assert_eq!(summary.n_restored, n_copies);
+
+    for ((file, _size), copies) in FILES.iter().zip(COPIES.iter()) {
+        let file_path = dir.path().join(file);
+        let inode     = fs::metadata(&file_path).unwrap().ino();
+
+        for copy in copies.iter() {
+            let path = dir.path().join(copy);
+            let meta = fs::metadata(&path).unwrap();
+
+            assert_ne!(meta.ino(), inode, "Copy '{}' is still linked", copy);
+            assert_eq!(meta.mode() & 0o7777, 0o600);
+            assert_eq!(meta.modified().unwrap(), mtime);
+            assert_eq!(
+                fs::read(&path).unwrap(), fs::read(&file_path).unwrap()
+            );
+        }
+    }
+
+    dir.close().unwrap();
+}
+
+#[test]
+fn test_deduplicator_journal_undo_hardlinks() {
+    let dir = create_basic_fs_structure(&DIRS, &FILES).unwrap();
+    hardlink_files(&dir, &FILES, &LINKS).unwrap();
+
+    // Copies in a higher priority root are kept, so that the linked files
+    // are the ones replaced
+    let keep = dir.path().join("keep");
+    fs::create_dir(&keep).unwrap();
+
+    for (idx, (file, _size)) in FILES.iter().enumerate() {
+        fs::copy(dir.path().join(file), keep.join(idx.to_string())).unwrap();
+    }
+
+    let journal = dir.path().join("journal");
+
+    Deduplicator::new(
+        ScanConfig::new().root(keep.to_str().unwrap())
+            .root(dir.path().to_str().unwrap())
+    )
+        .action(DedupAction::Hardlink)
+        .journal(journal.to_str().unwrap())
+        .run().unwrap();
+
+    assert_eq!(inode_of(&dir, FILE1), inode_of(&dir, "keep/0"));
+
+    // A file with the name of the temporary copy is not overwritten
+    let user_file = dir.path().join(format!("{}.fdedup-undo", FILE1));
+    fs::write(&user_file, "user data").unwrap();
+
+    let summary = undo_journal(
+        journal.to_str().unwrap(), true, false, &NullProgress,
+        &mut io::sink()
+    ).unwrap();
+
+    let n_links : usize = LINKS.iter().map( |x| x.len() ).sum();
+    assert_eq!(summary.n_restored, FILES.len() + n_links);
+
+    for (idx, ((file, _size), links)) in FILES.iter().zip(LINKS.iter())
+        .enumerate()
+    {
+        let inode = inode_of(&dir, file);
+        let meta  = fs::metadata(dir.path().join(file)).unwrap();
+
+        assert_ne!(inode, inode_of(&dir, &format!("keep/{}", idx)));
+        assert_eq!(meta.nlink() as usize, links.len() + 1);
+
+        for link in links.iter() {
+            assert_eq!(
+                inode_of(&dir, link), inode,
+                "Link '{}' is not restored as a link to '{}'", link, file
+            );
+        }
+    }
+
+    assert_eq!(fs::read_to_string(&user_file).unwrap(), "user data");
+
+    dir.close().unwrap();
+}
+
+#[test]
+fn test_deduplicator_journal_undo_symlinks() {
+    let dir = create_basic_fs_structure(&DIRS, &FILES).unwrap();
+    copy_files(&dir, &FILES, &COPIES).unwrap();
+
+    let journal = dir.path().join("journal");
+
+    Deduplicator::new(ScanConfig::new().root(dir.path().to_str().unwrap()))
+        .action(DedupAction::Symlink)
+        .journal(journal.to_str().unwrap())
+        .run().unwrap();
+
+    // Find a replaced copy and point it somewhere else
+    let changed = COPIES.iter().flat_map( |x| x.iter() )
+        .map( |x| { dir.path().join(x) } )
+        .find( |x| { fs::read_link(x).is_ok() } )
+        .unwrap();
+
+    fs::remove_file(&changed).unwrap();
+    std::os::unix::fs::symlink("elsewhere", &changed).unwrap();
+
+    let summary = undo_journal(
+        journal.to_str().unwrap(), true, false, &NullProgress,
+        &mut io::sink()
+    ).unwrap();
+
+    let n_copies : usize = COPIES.iter().map( |x| x.len() ).sum();
+
+    assert_eq!(summary.n_skipped,  1);
+    assert_eq!(summary.n_restored, n_copies - 1);
+    assert_eq!(
+        fs::read_link(&changed).unwrap().to_str().unwrap(), "elsewhere"
+    );
+
+    dir.close().unwrap();
+}
+
+fn set_mode(dir : &tempfile::TempDir, path : &str, mode : u32) {
+    fs::set_permissions(
+        dir.path().join(path), fs::Permissions::from_mode(mode)
+    ).unwrap();
+}
+
+fn inode_of(dir : &tempfile::TempDir, path : &str) -> u64 {
+    fs::metadata(dir.path().join(path)).unwrap().ino()
+}
+
+fn run_with_policy(dir : &tempfile::TempDir, policy : MetadataPolicy) {
+    Deduplicator::new(
+        ScanConfig::new().root(dir.path().join("dir1").to_str().unwrap())
+            .root(dir.path().join("dir3").to_str().unwrap())
+    )
+        .action(DedupAction::Hardlink)
+        .metadata_policy(policy)
+        .run().unwrap();
+}
+
+// FILE1 and all of its copies
+const GROUP : [&str; 5] = [
+    FILE1,
+    "dir1/test2",
+    "dir3/dir32/dir323/dir3231/test1",
+    "dir3/dir32/dir323/test1",
+    "dir3/dir32/dir323/test2",
+];
+
+#[test]
+fn test_deduplicator_metadata_refuse() {
+    let dir = create_basic_fs_structure(&DIRS, &FILES).unwrap();
+    copy_files(&dir, &FILES, &COPIES).unwrap();
+
+    for path in GROUP.iter() {
+        set_mode(&dir, path, 0o644);
+    }
+    set_mode(&dir, GROUP[4], 0o600);
+
+    run_with_policy(&dir, MetadataPolicy::Refuse);
+
+    // The leader comes from dir1, the first root
+    for path in GROUP[1..4].iter() {
+        assert_eq!(inode_of(&dir, path), inode_of(&dir, GROUP[0]));
+    }
+    assert_ne!(inode_of(&dir, GROUP[4]), inode_of(&dir, GROUP[0]));
+
+    dir.close().unwrap();
+}
+
+#[test]
+fn test_deduplicator_metadata_split() {
+    let dir = create_basic_fs_structure(&DIRS, &FILES).unwrap();
+    copy_files(&dir, &FILES, &COPIES).unwrap();
+
+    for (idx, path) in GROUP.iter().enumerate() {
+        set_mode(&dir, path, if idx < 2 { 0o644 } else { 0o600 });
+    }
+
+    run_with_policy(&dir, MetadataPolicy::Split);
+
+    assert_eq!(inode_of(&dir, GROUP[1]), inode_of(&dir, GROUP[0]));
+    assert_eq!(inode_of(&dir, GROUP[3]), inode_of(&dir, GROUP[2]));
+    assert_eq!(inode_of(&dir, GROUP[4]), inode_of(&dir, GROUP[2]));
+    assert_ne!(inode_of(&dir, GROUP[2]), inode_of(&dir, GROUP[0]));
+
+    dir.close().unwrap();
+}
+
+#[test]
+fn test_deduplicator_require_same_mode() {
+    let dir = create_basic_fs_structure(&DIRS, &FILES).unwrap();
+    copy_files(&dir, &FILES, &COPIES).unwrap();
+
+    for (idx, path) in GROUP.iter().enumerate() {
+        set_mode(&dir, path, if idx < 2 { 0o644 } else { 0o600 });
+    }
+
+    let report = Deduplicator::new(
+        ScanConfig::new().root(dir.path().to_str().unwrap())
+    )
+        .require_same(Attribute::Mode)
+        .search().unwrap();
+
+    let group_of = |path : &str| {
+        let path = dir.path().join(path);
+
+        report.groups.iter().position(
+            |group| {
+                group.iter().flat_map( |x| { x.paths.iter() } )
+                    .any( |x| { x.path == path.to_str().unwrap() } )
+            }
+        ).unwrap()
+    };
+
+    assert_eq!(group_of(GROUP[1]), group_of(GROUP[0]));
+    assert_eq!(group_of(GROUP[3]), group_of(GROUP[2]));
+    assert_eq!(group_of(GROUP[4]), group_of(GROUP[2]));
+    assert_ne!(group_of(GROUP[2]), group_of(GROUP[0]));
+
+    dir.close().unwrap();
+}
+
+#[test]
+fn test_deduplicator_metadata_reconcile() {
+    let dir = create_basic_fs_structure(&DIRS, &FILES).unwrap();
+    copy_files(&dir, &FILES, &COPIES).unwrap();
+
+    let old_time = UNIX_EPOCH + Duration::new(1_000_000_000, 0);
+    let new_time = UNIX_EPOCH + Duration::new(1_500_000_000, 0);
+
+    for (idx, path) in GROUP.iter().enumerate() {
+        set_mode(&dir, path, if idx == 2 { 0o640 } else { 0o600 });
+
+        fs::File::options().write(true).open(dir.path().join(path)).unwrap()
+            .set_times(FileTimes::new().set_modified(
+                if idx == 3 { new_time } else { old_time }
+            )).unwrap();
+    }
+
+    run_with_policy(&dir, MetadataPolicy::Reconcile);
+
+    let meta = fs::metadata(dir.path().join(GROUP[0])).unwrap();
+
+    for path in GROUP.iter() {
+        assert_eq!(inode_of(&dir, path), meta.ino());
+    }
+
+    assert_eq!(meta.mode() & 0o7777, 0o640);
+    assert_eq!(meta.modified().unwrap(), new_time);
+
+    dir.close().unwrap();
+}
+
+#[test]
+fn test_deduplicator_metadata_reconcile_undo() {
+    let dir = create_basic_fs_structure(&DIRS, &FILES).unwrap();
+    copy_files(&dir, &FILES, &COPIES).unwrap();
+
+    let old_time = UNIX_EPOCH + Duration::new(1_000_000_000, 0);
+    let new_time = UNIX_EPOCH + Duration::new(1_500_000_000, 0);
+
+    for (idx, path) in GROUP.iter().enumerate() {
+        set_mode(&dir, path, if idx == 2 { 0o640 } else { 0o600 });
+
+        fs::File::options().write(true).open(dir.path().join(path)).unwrap()
+            .set_times(FileTimes::new().set_modified(
+                if idx == 3 { new_time } else { old_time }
+            )).unwrap();
+    }
+
+    let journal = dir.path().join("journal");
+
+    Deduplicator::new(
+        ScanConfig::new().root(dir.path().join("dir1").to_str().unwrap())
+            .root(dir.path().join("dir3").to_str().unwrap())
+    )
+        .action(DedupAction::Hardlink)
+        .metadata_policy(MetadataPolicy::Reconcile)
+        .journal(journal.to_str().unwrap())
+        .run().unwrap();
+
+    let meta = fs::metadata(dir.path().join(GROUP[0])).unwrap();
+    assert_eq!(meta.mode() & 0o7777, 0o640);
+    assert_eq!(meta.modified().unwrap(), new_time);
+
+    undo_journal(
+        journal.to_str().unwrap(), true, false, &NullProgress,
+        &mut io::sink()
+    ).unwrap();
+
+    for (idx, path) in GROUP.iter().enumerate() {
+        let meta = fs::metadata(dir.path().join(path)).unwrap();
+
+        assert_eq!(
+            meta.mode() & 0o7777, if idx == 2 { 0o640 } else { 0o600 }
+        );
+        assert_eq!(
+            meta.modified().unwrap(), if idx == 3 { new_time } else { old_time }
+        );
+    }
+
+    dir.close().unwrap();
+}
+
+#[test]
+fn test_deduplicator_metadata_reconcile_failed_group() {
+    let dir = create_basic_fs_structure(&DIRS, &FILES).unwrap();
+    copy_files(&dir, &FILES, &COPIES).unwrap();
+
+    let old_time = UNIX_EPOCH + Duration::new(1_000_000_000, 0);
+    let new_time = UNIX_EPOCH + Duration::new(1_500_000_000, 0);
+
+    for ((file, _size), copies) in FILES.iter().zip(COPIES.iter()) {
+        for (idx, path) in copies.iter().chain(Some(file)).enumerate() {
+            fs::File::options().write(true).open(dir.path().join(path))
+                .unwrap()
+                .set_times(FileTimes::new().set_modified(
+                    if idx == 0 { new_time } else { old_time }
+                )).unwrap();
+        }
+    }
+
+    let dedup = Deduplicator::new(
+        ScanConfig::new().root(dir.path().to_str().unwrap())
+            .abort_on_error(false)
+    )
+        .action(DedupAction::Hardlink)
+        .metadata_policy(MetadataPolicy::Reconcile);
+
+    let report = dedup.search().unwrap();
+    let n_groups = report.groups.len();
+
+    // Linking to a removed leader fails, so the first group is not done
+    let group = &report.groups[0];
+    fs::remove_file(&group[find_group_leader_index(group)].paths[0].path)
+        .unwrap();
+
+    let summary = dedup.deduplicate(&report.groups).unwrap();
+    assert_eq!(summary.done_groups, (1..n_groups).collect::<Vec<usize>>());
+
+    for group in report.groups[1..].iter() {
+        let leader = &group[find_group_leader_index(group)];
+        let meta   = fs::metadata(&leader.paths[0].path).unwrap();
+
+        assert_eq!(meta.modified().unwrap(), new_time);
+    }
+
+    assert_eq!(dedup.skipped_errors().len(), 1);
+
+    dir.close().unwrap();
+}
+
+#[test]
+fn test_deduplicator_interrupted() {
+    let dir = create_basic_fs_structure(&DIRS, &FILES).unwrap();
+    copy_files(&dir, &FILES, &COPIES).unwrap();
+
+    let interrupt = Interrupt::new();
+    interrupt.set();
+
+    let report = Deduplicator::new(
+        ScanConfig::new().root(dir.path().to_str().unwrap())
+    )
+        .action(DedupAction::Hardlink)
+        .interrupt(interrupt)
+        .run().unwrap();
+
+    assert!(! report.complete);
+    assert!(report.groups.is_empty());
+    assert_eq!(report.dedup.n_done_files, 0);
+
+    dir.close().unwrap();
+}
+
+#[test]
+fn test_deduplicator_progress_events() {
+    let dir = create_basic_fs_structure(&DIRS, &FILES).unwrap();
+    copy_files(&dir, &FILES, &COPIES).unwrap();
+
+    let events = Rc::new(RefCell::new(Vec::new()));
+
+    Deduplicator::new(ScanConfig::new().root(dir.path().to_str().unwrap()))
+        .action(DedupAction::Hardlink)
+        .progress(Box::new(EventLog { events : events.clone() }))
+        .run().unwrap();
+
+    let n_copies : usize = COPIES.iter().map( |x| x.len() ).sum();
+    let mut null_events : Vec<String> = vec![
+        "start Grouping by first bytes".to_string(),
+        "start Grouping by last bytes".to_string(),
+        "start Grouping by hash (SHA512)".to_string(),
+        "start Deduplicating".to_string(),
+    ];
+    for _ in 0..n_copies {
+        null_events.push("remove".to_string());
+        null_events.push("hardlink".to_string());
+    }
+
+    assert_eq!(*events.borrow(), null_events);
+
+    dir.close().unwrap();
+}
+
+#[test]
+fn test_deduplicator_progress_events_failed_ops() {
+    let dir = create_basic_fs_structure(&DIRS, &FILES).unwrap();
+    copy_files(&dir, &FILES, &COPIES).unwrap();
+
+    let events = Rc::new(RefCell::new(Vec::new()));
+
+    let dedup = Deduplicator::new(
+        ScanConfig::new().root(dir.path().to_str().unwrap())
+            .abort_on_error(false)
+    )
+        .action(DedupAction::Hardlink)
+        .progress(Box::new(EventLog { events : events.clone() }));
+
+    let report = dedup.search().unwrap();
+
+    // Removing this file fails, so it must not be reported as linked
+    let group  = &report.groups[0];
+    let leader = find_group_leader_index(group);
+    fs::remove_file(&group[(leader + 1) % group.len()].paths[0].path)
+        .unwrap();
+
+    events.borrow_mut().clear();
+    dedup.deduplicate(&report.groups).unwrap();
+
+    let n_copies : usize = COPIES.iter().map( |x| x.len() ).sum();
+    let n_removed = events.borrow().iter().filter( |x| *x == "remove" ).count();
+
+    assert_eq!(n_removed, n_copies - 1);
+    assert_eq!(dedup.skipped_errors().len(), 1);
+
+    dir.close().unwrap();
+}
@@ -2,16 +2,29 @@ extern crate fdedup;
 #[macro_use] mod utils;
 
 use std::cmp::Ordering;
-
+use std::fs;
+use std::io::{self, Write};
+
+use fdedup::dups::compare::compare_files;
+use fdedup::dups::hasher::HashAlgorithm;
+use fdedup::dups::heuristics::{FileHash, FirstBytes, SampledBytes};
+use fdedup::dups::search::{
+    group_by_heuristic, remove_unique_entries_by_comparison,
+    remove_unique_entries_by_stage
+};
 use fdedup::fs_entry::FSEntry;
+use fdedup::utils::fiemap::first_physical_offset;
+use fdedup::utils::interrupt::Interrupt;
+use fdedup::utils::progress::NullProgress;
+use fdedup::utils::reader::Reader;
 use utils::{
     create_basic_fs_structure, hardlink_files, copy_files, find_duplicates,
-    create_null_entry
+    create_null_entry, collect_all_files
 };
 use utils::fs_skel::*;
 
 const N_READ : usize = 200;
-const ALGO   : crypto_hash::Algorithm = crypto_hash::Algorithm::SHA512;
+const ALGO   : HashAlgorithm = HashAlgorithm::Sha512;
 
 #[macro_export]
 macro_rules! compare_duplicates {
@@ -47,7 +60,7 @@ macro_rules! compare_duplicates {
     };
 }
 
-pub fn sort_duplicates(duplicate_groups : &mut Vec<Vec<FSEntry>>) {
+pub fn sort_duplicates(duplicate_groups : &mut [Vec<FSEntry>]) {
     for group in duplicate_groups.iter_mut() {
         for entry in group.iter_mut() {
             entry.paths.sort();
@@ -237,3 +250,163 @@ fn test_duplicate_search_complex_with_dev() {
     dir.close().unwrap();
 }
 
+
+fn find_duplicates_by_comparison(path : &str, max_compare : usize)
+    -> Vec<Vec<FSEntry>>
+{
+    let entries = collect_all_files(&[path]).unwrap();
+
+    let entries = remove_unique_entries_by_comparison(
+        entries, false, &FileHash(ALGO), &Reader::new().small_size(0),
+        max_compare,
+        &NullProgress, &Interrupt::new(), true
+    ).unwrap();
+
+    group_by_heuristic(entries, false)
+}
+
+#[test]
+fn test_duplicate_search_small_files() {
+    let dir = create_basic_fs_structure(&DIRS, &FILES).unwrap();
+    copy_files    (&dir, &FILES, &COPIES).unwrap();
+    hardlink_files(&dir, &FILES, &LINKS).unwrap();
+
+    // Every stage of small files is keyed by the digest of the first one
+    let reader  = Reader::new();
+    let entries = collect_all_files(&[dir.path().to_str().unwrap()]).unwrap();
+    let entries = remove_unique_entries_by_stage(
+        entries, false, &FirstBytes(N_READ), &reader, &NullProgress,
+        &Interrupt::new(), true
+    ).unwrap();
+
+    assert!(entries.iter().all( |x| { x.digest == x.hvalue } ));
+    assert!(entries.iter().all( |x| { x.digest.is_some() } ));
+
+    let mut test_duplicates = group_by_heuristic(entries, false);
+    let mut null_duplicates = calculate_null_duplicates(
+        &dir, &FILES, Some(&LINKS), Some(&COPIES)
+    );
+
+    compare_duplicates!(test_duplicates, null_duplicates);
+
+    dir.close().unwrap();
+}
+
+#[test]
+fn test_duplicate_search_by_comparison() {
+    let dir = create_basic_fs_structure(&DIRS, &FILES).unwrap();
+    copy_files    (&dir, &FILES, &COPIES).unwrap();
+    hardlink_files(&dir, &FILES, &LINKS).unwrap();
+
+    // Some groups are compared and some hashed, or all are compared
+    for max_compare in [ 3, 8 ].iter() {
+        let mut test_duplicates = find_duplicates_by_comparison(
+            dir.path().to_str().unwrap(), *max_compare
+        );
+
+        let mut null_duplicates = calculate_null_duplicates(
+            &dir, &FILES, Some(&LINKS), Some(&COPIES)
+        );
+
+        compare_duplicates!(test_duplicates, null_duplicates);
+    }
+
+    dir.close().unwrap();
+}
+
+#[test]
+fn test_compare_files() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let data : Vec<u8> = (0..(200 * 1024)).map( |x| x as u8 ).collect();
+    let mut changed = data.clone();
+    changed[150 * 1024] ^= 1;
+
+    let contents : [&[u8]; 5] = [ &data, &changed, &data, &[], &[] ];
+    let paths : Vec<String> = (0..contents.len())
+        .map( |x| dir.path().join(x.to_string()).to_str().unwrap().into() )
+        .collect();
+
+    for (path, content) in paths.iter().zip(contents.iter()) {
+        fs::write(path, content).unwrap();
+    }
+
+    let paths : Vec<&str> = paths.iter().map( |x| x.as_str() ).collect();
+
+    // I/O hints do not change what is read
+    for hints in [ true, false ].iter() {
+        let classes = compare_files(&paths, &Reader::new().hints(*hints))
+            .unwrap();
+
+        assert_eq!(classes[0], classes[2]);
+        assert_eq!(classes[3], classes[4]);
+        assert_ne!(classes[0], classes[1]);
+        assert_ne!(classes[0], classes[3]);
+        assert_ne!(classes[1], classes[3]);
+    }
+
+    dir.close().unwrap();
+}
+
+#[test]
+fn test_duplicate_search_by_samples() {
+    let dir = tempfile::tempdir().unwrap();
+
+    // Headers and trailers are equal, only samples tell files apart
+    let data : Vec<u8> = (0..(1 << 20)).map( |x| x as u8 ).collect();
+    let mut changed = data.clone();
+    changed[1 << 19] ^= 1;
+
+    let contents : [&[u8]; 3] = [ &data, &changed, &data ];
+
+    for (idx, content) in contents.iter().enumerate() {
+        fs::write(dir.path().join(idx.to_string()), content).unwrap();
+    }
+
+    let entries = collect_all_files(&[dir.path().to_str().unwrap()]).unwrap();
+    let heuristic = SampledBytes { n_samples : 3, n_read : N_READ };
+
+    let entries = remove_unique_entries_by_stage(
+        entries, false, &heuristic, &Reader::new(), &NullProgress,
+        &Interrupt::new(), true
+    ).unwrap();
+
+    let mut paths : Vec<String> = entries.iter()
+        .map( |x| x.paths[0].path.clone() )
+        .collect();
+    paths.sort();
+
+    assert_eq!(paths, vec![
+        dir.path().join("0").to_str().unwrap().to_string(),
+        dir.path().join("2").to_str().unwrap().to_string(),
+    ]);
+
+    dir.close().unwrap();
+}
+
+#[test]
+fn test_first_physical_offset() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let empty = dir.path().join("empty");
+    let full  = dir.path().join("full");
+    fs::write(&empty, []).unwrap();
+
+    // Extents of data that is not yet written have no known offset
+    let mut file = fs::File::create(&full).unwrap();
+    file.write_all(&[ 1u8; 64 * 1024 ]).unwrap();
+    file.sync_all().unwrap();
+
+    // Not every filesystem of temporary directories supports FIEMAP
+    match first_physical_offset(full.to_str().unwrap()) {
+        Err(e) if e.kind() == io::ErrorKind::Unsupported => {},
+        other => {
+            assert!(other.unwrap().is_some());
+            assert_eq!(
+                first_physical_offset(empty.to_str().unwrap()).unwrap(), None
+            );
+        },
+    }
+
+    dir.close().unwrap();
+}
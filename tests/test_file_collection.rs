@@ -2,7 +2,13 @@ extern crate fdedup;
 #[macro_use] mod utils;
 
 use std::cmp::Ordering;
-use fdedup::fs_entry::FSEntry;
+use std::ffi::OsStr;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::symlink;
+
+use fdedup::Error;
+use fdedup::fs_entry::{FSEntry, Priority};
 use utils::{
     collect_all_files, create_basic_fs_structure, hardlink_files, copy_files,
     create_null_entry
@@ -33,11 +39,16 @@ macro_rules! compare_entries {
                 "Actual and expected fs entries have different paths.\
                  \nActual entry: {:?}\nExpected entry: {:?}", t, n
             );
+            assert_eq!(
+                t.priority, n.priority,
+                "Actual and expected fs entries have different priorities.\
+                 \nActual entry: {:?}\nExpected entry: {:?}", t, n
+            );
         }
     };
 }
 
-pub fn sort_entries(entries : &mut Vec<FSEntry>) {
+pub fn sort_entries(entries : &mut [FSEntry]) {
     for entry in entries.iter_mut() {
         entry.paths.sort();
     }
@@ -52,6 +63,22 @@ pub fn sort_entries(entries : &mut Vec<FSEntry>) {
     );
 }
 
+pub fn set_null_priorities(
+    entries : &mut [FSEntry], root : &str, priority : Priority
+)
+{
+    for entry in entries.iter_mut() {
+        for path in entry.paths.iter_mut() {
+            if path.path.starts_with(root) {
+                path.priority = priority;
+            }
+        }
+
+        entry.priority =
+            entry.paths.iter().map( |x| x.priority ).min().unwrap();
+    }
+}
+
 pub fn create_null_entries(
     dir    : &tempfile::TempDir,
     files  : &[(&str, u64)],
@@ -172,3 +199,83 @@ fn test_file_collection_complex() {
     dir.close().unwrap();
 }
 
+
+#[test]
+fn test_file_collection_nested_roots() {
+    let dir = create_basic_fs_structure(&DIRS, &FILES).unwrap();
+    hardlink_files(&dir, &FILES, &LINKS).unwrap();
+    copy_files    (&dir, &FILES, &COPIES).unwrap();
+
+    let nested_root = dir.path().join("dir3");
+
+    let mut test_entries = collect_all_files(&[
+        dir.path().to_str().unwrap(),
+        nested_root.to_str().unwrap(),
+    ]).unwrap();
+
+    let mut null_entries =
+        create_null_entries(&dir, &FILES, Some(&LINKS), Some(&COPIES));
+
+    set_null_priorities(&mut null_entries, nested_root.to_str().unwrap(), 1);
+
+    compare_entries!(test_entries, null_entries);
+
+    dir.close().unwrap();
+}
+
+#[test]
+fn test_file_collection_path_priorities() {
+    let dir = create_basic_fs_structure(&DIRS, &FILES).unwrap();
+    hardlink_files(&dir, &FILES, &LINKS).unwrap();
+
+    let nested_root = dir.path().join("dir3");
+
+    let mut test_entries = collect_all_files(&[
+        nested_root.to_str().unwrap(),
+        dir.path().to_str().unwrap(),
+    ]).unwrap();
+
+    let mut null_entries =
+        create_null_entries(&dir, &FILES, Some(&LINKS), None);
+
+    set_null_priorities(&mut null_entries, dir.path().to_str().unwrap(), 1);
+    set_null_priorities(&mut null_entries, nested_root.to_str().unwrap(), 0);
+
+    compare_entries!(test_entries, null_entries);
+
+    for entry in test_entries.iter() {
+        let leader = entry.leader_path();
+
+        assert_eq!(
+            leader.priority, entry.priority,
+            "Leader path priority differs from entry priority: {:?}", entry
+        );
+
+        if entry.priority == 0 {
+            assert!(
+                leader.path.starts_with(nested_root.to_str().unwrap()),
+                "Leader path is not in the highest priority root: {:?}", entry
+            );
+        }
+    }
+
+    dir.close().unwrap();
+}
+
+#[test]
+fn test_file_collection_non_utf8_root() {
+    let dir = tempfile::tempdir().unwrap();
+
+    // The root itself is valid UTF-8, the path it resolves to is not
+    let target = dir.path().join(OsStr::from_bytes(b"dir\xff"));
+    let link   = dir.path().join("link");
+    fs::create_dir(&target).unwrap();
+    symlink(&target, &link).unwrap();
+
+    assert!(matches!(
+        collect_all_files(&[ link.to_str().unwrap() ]),
+        Err(Error::Config(_))
+    ));
+
+    dir.close().unwrap();
+}
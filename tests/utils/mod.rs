@@ -1,22 +1,23 @@
 extern crate fdedup;
-extern crate crypto_hash;
 extern crate fastrand;
 extern crate tempfile;
 
 pub mod fs_skel;
 
-use std::collections::HashMap;
 use std::fs::{File, create_dir_all};
 use std::io::{self, Write};
 use std::iter::repeat_with;
 use std::path::Path;
 
-use fdedup::fs_entry::{FSEntry, INode, Dev, collect_files};
-use fdedup::dups::heuristics::{
-    fn_first_bytes, fn_last_bytes, fn_file_hash
-};
+use fdedup::ScanConfig;
+use fdedup::dups::hasher::HashAlgorithm;
+use fdedup::fs_entry::{FSEntry, FSPath};
+use fdedup::utils::interrupt::Interrupt;
+use fdedup::utils::progress::NullProgress;
+use fdedup::utils::reader::Reader;
+use fdedup::dups::heuristics::{FirstBytes, LastBytes, FileHash};
 use fdedup::dups::search::{
-    group_by_heuristic, remove_unique_entries_by_heuristic_fn
+    group_by_heuristic, remove_unique_entries_by_stage
 };
 
 pub fn create_random_file(path : &Path, size : u64) -> io::Result<()> {
@@ -32,45 +33,63 @@ pub fn create_random_file(path : &Path, size : u64) -> io::Result<()> {
     Ok(())
 }
 
-pub fn collect_all_files(paths : &[&str]) -> io::Result<Vec<FSEntry>>{
-    let mut files_map : HashMap<(Dev, INode), FSEntry> = HashMap::new();
+#[allow(dead_code)]
+pub fn collect_all_files(paths : &[&str]) -> fdedup::Result<Vec<FSEntry>>{
+    collect_all_files_with_references(paths, &[])
+}
+
+pub fn collect_all_files_with_references(
+    paths : &[&str], references : &[&str]
+) -> fdedup::Result<Vec<FSEntry>>
+{
+    let mut config = ScanConfig::new();
 
-    for (idx, path) in paths.iter().enumerate() {
-        collect_files(
-            path, &mut files_map, true, false, false,
-            &Vec::new(), &Vec::new(), None, None, idx as u32
-        )?;
+    for path in paths.iter() {
+        config = config.root(path);
     }
 
-    let result : Vec<FSEntry> =
-        files_map.into_iter().map( | (_, v) | { v } ).collect();
+    for path in references.iter() {
+        config = config.reference(path);
+    }
 
-    Ok(result)
+    config.collect(&NullProgress, &Interrupt::new())
 }
 
 #[allow(dead_code)]
 pub fn find_duplicates(
     paths : &[&str], cmp_dev : bool, n_read : usize,
-    hash : crypto_hash::Algorithm
-) -> io::Result<Vec<Vec<FSEntry>>>
+    hash : HashAlgorithm
+) -> fdedup::Result<Vec<Vec<FSEntry>>>
+{
+    find_duplicates_with_references(paths, &[], cmp_dev, n_read, hash)
+}
+
+#[allow(dead_code)]
+pub fn find_duplicates_with_references(
+    paths : &[&str], references : &[&str], cmp_dev : bool, n_read : usize,
+    hash : HashAlgorithm
+) -> fdedup::Result<Vec<Vec<FSEntry>>>
 {
-    let mut result = collect_all_files(paths)?;
+    let mut result = collect_all_files_with_references(paths, references)?;
+    let interrupt  = Interrupt::new();
+    // Test files are small, they would skip byte heuristics otherwise
+    let reader     = Reader::new().small_size(0);
 
     if n_read > 0 {
-        result = remove_unique_entries_by_heuristic_fn(
-            result, cmp_dev, "first bytes", false, false,
-            Box::new(move | entry | { fn_first_bytes(entry, n_read) })
+        result = remove_unique_entries_by_stage(
+            result, cmp_dev, &FirstBytes(n_read), &reader, &NullProgress,
+            &interrupt, false
         )?;
 
-        result = remove_unique_entries_by_heuristic_fn(
-            result, cmp_dev, "last bytes", false, false,
-            Box::new(move | entry | { fn_last_bytes(entry, n_read) })
+        result = remove_unique_entries_by_stage(
+            result, cmp_dev, &LastBytes(n_read), &reader, &NullProgress,
+            &interrupt, false
         )?;
     }
 
-    result = remove_unique_entries_by_heuristic_fn(
-        result, cmp_dev, "last bytes", false, false,
-        Box::new(move | entry | { fn_file_hash(entry, hash) })
+    result = remove_unique_entries_by_stage(
+        result, cmp_dev, &FileHash(hash), &reader, &NullProgress, &interrupt,
+        false
     )?;
 
     Ok(group_by_heuristic(result, cmp_dev))
@@ -137,14 +156,16 @@ pub fn create_null_entry(
 ) -> FSEntry {
     let mut result = FSEntry::new(
         0, 0, size, 0,
-        dir.path().join(file).to_str().unwrap().to_string()
+        FSPath::new(
+            dir.path().join(file).to_str().unwrap().to_string(), 0, false
+        )
     );
 
     if let Some(file_links) = links {
         for link in file_links.iter() {
-            result.add_path(
-                dir.path().join(link).to_str().unwrap().to_string()
-            );
+            result.add_path(FSPath::new(
+                dir.path().join(link).to_str().unwrap().to_string(), 0, false
+            ));
         }
     }
 